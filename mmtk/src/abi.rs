@@ -4,8 +4,13 @@ use mmtk::scheduler::GCWorker;
 use mmtk::util::{Address, ObjectReference, VMMutatorThread, VMWorkerThread};
 
 // For the C binding
+#[cfg(not(feature = "no_hidden_header"))]
 pub const OBJREF_OFFSET: usize = 8;
+#[cfg(feature = "no_hidden_header")]
+pub const OBJREF_OFFSET: usize = 0;
 pub const MIN_OBJ_ALIGN: usize = 8; // Even on 32-bit machine.  A Ruby object is at least 40 bytes large.
+/// The largest payload alignment `get_payload_alignment` may request.  One cache line.
+pub const MAX_OBJ_ALIGN: usize = 64;
 
 pub const GC_THREAD_KIND_WORKER: libc::c_int = 1;
 
@@ -13,11 +18,23 @@ pub const HIDDEN_SIZE_MASK: usize = 0x0000FFFFFFFFFFFF;
 
 // Should keep in sync with C code.
 const RUBY_FL_EXIVAR: usize = 1 << 10;
+const RUBY_T_MASK: usize = 0x1f;
 
 // An opaque type for the C counterpart.
 #[allow(non_camel_case_types)]
 pub struct st_table;
 
+/// Convert a Ruby `VALUE` to an `ObjectReference`.  Returns `None` if `value` is a special
+/// constant, such as `Qfalse`, `Qnil`, fixnums, flonums and static symbols.  All of them are
+/// either zero or not aligned to `MIN_OBJ_ALIGN`.
+pub fn value_to_objref(value: Address) -> Option<ObjectReference> {
+    if value.is_aligned_to(MIN_OBJ_ALIGN) {
+        ObjectReference::from_raw_address(value)
+    } else {
+        None
+    }
+}
+
 #[repr(C)]
 pub struct HiddenHeader {
     pub prefix: usize,
@@ -71,17 +88,77 @@ impl RubyObjectAccess {
         self.suffix_addr() + Self::suffix_size()
     }
 
+    /// Catch reads of the size of the old copy of a forwarded object, whose hidden header (or
+    /// flags, with the `no_hidden_header` feature) has been overwritten by the forwarding pointer.
+    #[inline(always)]
+    fn assert_not_forwarded(&self) {
+        extra_assert!(
+            self.forwarded_to().is_none(),
+            "Reading the size of object {} which has been forwarded",
+            self.objref
+        );
+    }
+
+    #[cfg(not(feature = "no_hidden_header"))]
     fn hidden_header(&self) -> &'static HiddenHeader {
         unsafe { self.obj_start().as_ref() }
     }
 
-    #[allow(unused)] // Maybe we need to mutate the hidden header in the future.
+    #[cfg(not(feature = "no_hidden_header"))]
     fn hidden_header_mut(&self) -> &'static mut HiddenHeader {
         unsafe { self.obj_start().as_mut_ref() }
     }
 
+    /// Return the new copy of the object if it has been forwarded in the current GC.
+    fn forwarded_to(&self) -> Option<ObjectReference> {
+        if crate::BINDING_FAST
+            .world_stopped
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            self.objref.get_forwarded_object()
+        } else {
+            None
+        }
+    }
+
+    /// Return the payload size.  Must not be called on the old copy of a forwarded object.  Use
+    /// `payload_size_maybe_forwarded` if the object may have been forwarded.
     pub fn payload_size(&self) -> usize {
-        self.hidden_header().payload_size()
+        self.assert_not_forwarded();
+        #[cfg(not(feature = "no_hidden_header"))]
+        {
+            self.hidden_header().payload_size()
+        }
+        #[cfg(feature = "no_hidden_header")]
+        {
+            // `RubyBinding::new` checks that the upcall is present.
+            (upcalls().get_payload_size.unwrap())(self.objref)
+        }
+    }
+
+    /// Return the payload size.  Unlike `payload_size`, this also works on the old copy of a
+    /// forwarded object, and returns the size of the old copy even if the object was resized
+    /// when copied.  Slower than `payload_size`.  Intended for heap walkers and debugging.
+    pub fn payload_size_maybe_forwarded(&self) -> usize {
+        let Some(new_objref) = self.forwarded_to() else {
+            return self.payload_size();
+        };
+        crate::binding()
+            .resized_objects
+            .get(self.objref)
+            .unwrap_or_else(|| Self::from_objref(new_objref).payload_size())
+    }
+
+    /// Overwrite the payload size in the hidden header.  Only used when copying an object into a
+    /// slot of a different size.  Without the hidden header, the VM derives the size from the
+    /// object itself, and is responsible for updating it in `after_copy`.
+    pub fn set_payload_size(&self, payload_size: usize) {
+        debug_assert_eq!(payload_size & !HIDDEN_SIZE_MASK, 0);
+        #[cfg(not(feature = "no_hidden_header"))]
+        {
+            let header = self.hidden_header_mut();
+            header.prefix = (header.prefix & !HIDDEN_SIZE_MASK) | payload_size;
+        }
     }
 
     fn flags_field(&self) -> Address {
@@ -96,8 +173,14 @@ impl RubyObjectAccess {
         (self.load_flags() & RUBY_FL_EXIVAR) != 0
     }
 
+    /// The `ruby_value_type` of the object.
+    pub fn builtin_type(&self) -> usize {
+        self.load_flags() & RUBY_T_MASK
+    }
+
     pub fn prefix_size() -> usize {
-        // Currently, a hidden size field of word size is placed before each object.
+        // By default, a hidden size field of word size is placed before each object.  It is
+        // absent with the `no_hidden_header` feature.
         OBJREF_OFFSET
     }
 
@@ -110,6 +193,12 @@ impl RubyObjectAccess {
         Self::prefix_size() + self.payload_size() + Self::suffix_size()
     }
 
+    /// Like `object_size`, but also works on the old copy of a forwarded object.  See
+    /// `payload_size_maybe_forwarded`.
+    pub fn object_size_maybe_forwarded(&self) -> usize {
+        Self::prefix_size() + self.payload_size_maybe_forwarded() + Self::suffix_size()
+    }
+
     pub fn get_givtbl(&self) -> *mut libc::c_void {
         self.get_original_givtbl()
             .or_else(|| {
@@ -293,6 +382,26 @@ impl GCThreadTLS {
     }
 }
 
+/// The reason code for PPPs registered without a reason.
+pub const PPP_REASON_UNKNOWN: u32 = 0;
+
+/// Statistics of PPPs registered with a given reason.
+#[repr(C)]
+pub struct PPPStats {
+    /// The number of currently registered PPPs.
+    pub num_ppps: usize,
+    /// The number of children of those PPPs pinned in the last moving GC.
+    pub num_pinned_children: usize,
+}
+
+/// An object moved by the GC, reported to move listeners.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MovedObject {
+    pub old: ObjectReference,
+    pub new: ObjectReference,
+}
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct RawVecOfObjRef {
@@ -329,6 +438,17 @@ impl From<Vec<ObjectReference>> for RawVecOfObjRef {
 pub struct RubyBindingOptions {
     pub ractor_check_mode: bool,
     pub suffix_size: usize,
+    /// True if the VM installs return barriers in the control-frame stack of each thread so
+    /// that it can tell which frames changed since the last stack scan.
+    pub return_barrier: bool,
+    /// True if dead `obj_free` candidates are kept alive in a pending queue, and mutators call
+    /// `obj_free` on them with `mmtk_drain_pending_obj_free` instead of GC workers calling it
+    /// during the pause.  Weak references to them are cleared in the GC in which they die, and
+    /// objects still pending when the next GC starts are freed by GC workers in that GC.
+    pub lazy_obj_free: bool,
+    /// A bit mask of `ruby_value_type`.  The `after_copy` upcall is only called for objects of
+    /// the types whose bits are set.
+    pub after_copy_types: u32,
 }
 
 #[repr(C)]
@@ -350,25 +470,39 @@ pub struct RubyUpcalls {
     pub scan_global_tbl_roots: extern "C" fn(),
     pub scan_yjit_roots: extern "C" fn(),
     pub scan_global_symbols_roots: extern "C" fn(),
+    /// Scan the finalizer procs, i.e. the records of the finalizer table.  The keys are not roots.
+    /// Objects with finalizers are registered with `mmtk_add_finalizer`, and are resurrected by
+    /// mmtk-core when they die.
     pub scan_finalizer_tbl_roots: extern "C" fn(),
     pub scan_obj_to_id_tbl_roots: extern "C" fn(),
     pub scan_misc_roots: extern "C" fn(),
     pub scan_final_jobs_roots: extern "C" fn(),
     pub scan_roots_in_mutator_thread:
         extern "C" fn(mutator_tls: VMMutatorThread, worker_tls: VMWorkerThread),
+    /// Like `scan_roots_in_mutator_thread`, but only scan the stack frames above the return
+    /// barrier, i.e. frames that may have changed since the last stack scan, and move the return
+    /// barrier to the top frame.  Frames whose local variables may be written by blocks running in
+    /// callee frames must be treated as changed.  Optional, but required if
+    /// `RubyBindingOptions::return_barrier` is true.  Only called in that case.
+    pub scan_roots_in_mutator_thread_partially:
+        Option<extern "C" fn(mutator_tls: VMMutatorThread, worker_tls: VMWorkerThread)>,
     pub is_no_longer_ppp: extern "C" fn(ObjectReference) -> bool,
     pub scan_object_ruby_style: extern "C" fn(object: ObjectReference),
     pub call_gc_mark_children: extern "C" fn(object: ObjectReference),
     pub call_obj_free: extern "C" fn(object: ObjectReference),
-    pub cleanup_generic_iv_tbl: extern "C" fn(),
+    /// Return true if `call_obj_free` can be called on `object` in parallel with other GC workers
+    /// calling `call_obj_free` on other objects.  It is usually decided by the type of the object.
+    /// The VM must be able to answer it for dead objects.
+    pub can_free_in_parallel: extern "C" fn(object: ObjectReference) -> bool,
     pub get_original_givtbl: extern "C" fn(object: ObjectReference) -> *mut libc::c_void,
-    pub move_givtbl: extern "C" fn(old_objref: ObjectReference, new_objref: ObjectReference),
     pub vm_live_bytes: extern "C" fn() -> usize,
+    /// Create finalizer jobs for `objects`, which are dead objects with finalizers.  They have
+    /// been resurrected by mmtk-core, and they are at their new addresses if moved.  Called in
+    /// `resume_mutators` before mutators are resumed.  It must not allocate objects in the MMTk
+    /// heap.
+    pub enqueue_finalizer_jobs: extern "C" fn(objects: *const ObjectReference, len: usize),
     pub update_frozen_strings_table: extern "C" fn(),
-    pub update_finalizer_and_obj_id_tables: extern "C" fn(),
     pub update_global_symbols_table: extern "C" fn(),
-    pub update_overloaded_cme_table: extern "C" fn(),
-    pub update_ci_table: extern "C" fn(),
     pub get_generic_iv_tbl: extern "C" fn() -> *mut st_table,
     pub get_frozen_strings_table: extern "C" fn() -> *mut st_table,
     pub get_finalizer_table: extern "C" fn() -> *mut st_table,
@@ -378,6 +512,7 @@ pub struct RubyUpcalls {
     pub get_overloaded_cme_table: extern "C" fn() -> *mut st_table,
     pub get_ci_table: extern "C" fn() -> *mut st_table,
     pub st_get_num_entries: extern "C" fn(table: *const st_table) -> usize,
+    pub st_get_rebuilds_num: extern "C" fn(table: *const st_table) -> usize,
     pub st_get_size_info: extern "C" fn(
         table: *const st_table,
         entries_start: *mut libc::size_t,
@@ -394,6 +529,47 @@ pub struct RubyUpcalls {
     ) -> usize,
     pub st_update_bins_range:
         extern "C" fn(table: *mut st_table, begin: libc::size_t, end: libc::size_t) -> usize,
+    /// Recompute the hashes of all entries from their keys, and rebuild the bins.
+    pub st_rehash: extern "C" fn(table: *mut st_table),
+    /// Like `st_update_entries_range`, but for `generic_iv_tbl_`.  Remove entries of dead
+    /// objects and free their `gen_ivtbl`, and forward the keys of the remaining entries if
+    /// `forward` is true.
+    pub update_generic_iv_tbl_entries_range: extern "C" fn(
+        table: *mut st_table,
+        begin: libc::size_t,
+        end: libc::size_t,
+        forward: bool,
+    ) -> usize,
+    /// Fix up an object after it is copied from `from` to `to`, such as interior pointers into
+    /// its embedded buffer.  It may read `from`, but must only write to `to`.  Optional, and only
+    /// called for types selected by `RubyBindingOptions::after_copy_types`.
+    pub after_copy: Option<extern "C" fn(from: ObjectReference, to: ObjectReference)>,
+    /// Return the payload size `object` should have when copied, e.g. smaller if its content can
+    /// be embedded in a smaller slot, or larger if it needs more room.  Optional.  If it returns a
+    /// size different from the current payload size, only the common part of the payload is
+    /// copied, and `after_copy` is always called to fix up the new copy, so `after_copy` must be
+    /// present, too.
+    pub get_optimal_payload_size: Option<extern "C" fn(object: ObjectReference) -> usize>,
+    /// Return the alignment of the payload of `object`, which must be a power of two.  Optional.
+    /// If absent, or if it returns a value smaller than `MIN_OBJ_ALIGN`, the payload is aligned to
+    /// `MIN_OBJ_ALIGN`.  Must not exceed `MAX_OBJ_ALIGN`.  Respected when the object is copied.
+    pub get_payload_alignment: Option<extern "C" fn(object: ObjectReference) -> usize>,
+    /// Return the payload size of `object`, computed from its type and size class.  Required with
+    /// the `no_hidden_header` feature, and unused otherwise.  Called frequently, so it should be
+    /// fast.  The binding never calls it on the old copy of a forwarded object, whose first payload
+    /// word (flags) has been overwritten by the forwarding pointer, so it may read the flags.
+    pub get_payload_size: Option<extern "C" fn(object: ObjectReference) -> usize>,
+    /// Make the bins in the range `[begin, end)` empty.  Optional.  If both this and
+    /// `st_rehash_entries_range` are present, tables whose keys are hashed by address are rehashed
+    /// in parallel chunks instead of with `st_rehash`.
+    pub st_clear_bins_range:
+        Option<extern "C" fn(table: *mut st_table, begin: libc::size_t, end: libc::size_t)>,
+    /// Recompute the hashes of the live entries in the range `[begin, end)` from their keys, and
+    /// insert them into the bins.  Only called after all bins have been cleared with
+    /// `st_clear_bins_range`.  It is called concurrently for disjoint ranges of the same table, so
+    /// empty bins must be claimed with atomic compare-and-swap.  Optional.
+    pub st_rehash_entries_range:
+        Option<extern "C" fn(table: *mut st_table, begin: libc::size_t, end: libc::size_t)>,
 }
 
 unsafe impl Sync for RubyUpcalls {}
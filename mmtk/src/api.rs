@@ -5,18 +5,24 @@ use std::ffi::CStr;
 use std::sync::atomic::Ordering;
 
 use crate::abi;
+use crate::abi::st_table;
+use crate::abi::value_to_objref;
 use crate::abi::HiddenHeader;
+use crate::abi::PPPStats;
 use crate::abi::RawVecOfObjRef;
 use crate::abi::RubyBindingOptions;
 use crate::abi::RubyObjectAccess;
 use crate::binding;
 use crate::binding::RubyBinding;
+use crate::dead_objects::DeadObjectCallback;
 use crate::mmtk;
+use crate::move_listeners::MoveListenerCallback;
 use crate::Ruby;
 use crate::RubySlot;
 use crate::BINDING_FAST;
 use mmtk::memory_manager;
 use mmtk::memory_manager::mmtk_init;
+use mmtk::plan::BarrierSelector;
 use mmtk::util::alloc::AllocatorInfo;
 use mmtk::util::alloc::AllocatorSelector;
 use mmtk::util::api_util::NullableObjectReference;
@@ -37,9 +43,7 @@ pub type RubyMutator = Mutator<Ruby>;
 /// This instance shall be consumed by `mmtk_init_binding`.
 #[no_mangle]
 pub extern "C" fn mmtk_builder_default() -> *mut MMTKBuilder {
-    let mut builder = MMTKBuilder::new_no_env_vars();
-    // We don't use the Java-style finalization framework in mmtk-core.
-    builder.options.no_finalizer.set(true);
+    let builder = MMTKBuilder::new_no_env_vars();
     Box::into_raw(Box::new(builder))
 }
 
@@ -107,6 +111,42 @@ pub extern "C" fn mmtk_builder_is_sticky_immix(builder: *mut MMTKBuilder) -> boo
     matches!(*builder.options.plan, PlanSelector::StickyImmix)
 }
 
+/// Set a tuning option of the binding, such as `st_entries_chunk_size`.  It overrides the
+/// corresponding environment variable.  Return false and print a warning if the name is unknown
+/// or the value is invalid.
+///
+/// Options are applied when `mmtk_init_binding` is called, so it must be called before that.
+#[no_mangle]
+pub extern "C" fn mmtk_builder_set_binding_option(
+    _builder: *mut MMTKBuilder,
+    name: *const libc::c_char,
+    value: *const libc::c_char,
+) -> bool {
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
+    let value = unsafe { CStr::from_ptr(value) }.to_str().unwrap();
+    match crate::options::set_builder_override(name, value) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to set binding option {name}={value}: {e}");
+            false
+        }
+    }
+}
+
+/// Get the effective value of a tuning option of the binding.  Booleans are 0 or 1.  Return false
+/// if the name is unknown.
+#[no_mangle]
+pub extern "C" fn mmtk_get_binding_option(name: *const libc::c_char, value: *mut usize) -> bool {
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
+    match binding().tuning.get(name) {
+        Some(v) => {
+            unsafe { *value = v };
+            true
+        }
+        None => false,
+    }
+}
+
 /// Build an MMTk instance.
 ///
 /// -   `builder` is the pointer to the `MMTKBuilder` instance created by the
@@ -296,6 +336,49 @@ pub extern "C" fn mmtk_get_all_obj_free_candidates() -> RawVecOfObjRef {
     RawVecOfObjRef::from_vec(vec)
 }
 
+/// Call `obj_free` on at most `max` dead objects in the pending queue.  Return the number of
+/// objects remaining in the queue.  Only useful if `RubyBindingOptions::lazy_obj_free` is true.
+/// Objects remaining in the queue when the next GC starts are freed by that GC.
+///
+/// The VM should call it at allocation slow paths or safepoints.  `obj_free` must not reach a
+/// safepoint, so no GC can reclaim the objects while they are being freed.
+#[no_mangle]
+pub extern "C" fn mmtk_drain_pending_obj_free(max: usize) -> usize {
+    binding().weak_proc.drain_pending_obj_free(max)
+}
+
+/// Start reporting dead objects to `callback` after each GC.  One in every `sample_period`
+/// objects passed to `mmtk_track_object` is tracked.  Replaces the previous callback, if any.
+#[no_mangle]
+pub extern "C" fn mmtk_register_dead_object_callback(
+    callback: DeadObjectCallback,
+    data: *mut libc::c_void,
+    sample_period: usize,
+) {
+    binding()
+        .dead_object_tracker
+        .register_callback(callback, data, sample_period)
+}
+
+/// Stop reporting dead objects.  Return false if no callback was registered.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_dead_object_callback() -> bool {
+    binding().dead_object_tracker.unregister_callback()
+}
+
+/// Return true if the VM should call `mmtk_track_object` for newly allocated objects.
+#[no_mangle]
+pub extern "C" fn mmtk_is_tracking_dead_objects() -> bool {
+    binding().dead_object_tracker.is_enabled()
+}
+
+/// Track a newly allocated object so that its death will be reported.  Does nothing unless a
+/// dead object callback is registered.
+#[no_mangle]
+pub extern "C" fn mmtk_track_object(object: ObjectReference) {
+    binding().dead_object_tracker.track_object(object)
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_free_raw_vec_of_obj_ref(raw_vec: RawVecOfObjRef) {
     unsafe { raw_vec.into_vec() };
@@ -303,13 +386,136 @@ pub extern "C" fn mmtk_free_raw_vec_of_obj_ref(raw_vec: RawVecOfObjRef) {
 
 #[no_mangle]
 pub extern "C" fn mmtk_register_ppp(object: ObjectReference) {
-    crate::binding().ppp_registry.register(object)
+    crate::binding()
+        .ppp_registry
+        .register(object, abi::PPP_REASON_UNKNOWN)
+}
+
+/// Register `object` as a PPP.  `reason` is a VM-defined code telling why it is a PPP, such as
+/// "T_DATA without dcompact".  Statistics are collected per reason.
+#[no_mangle]
+pub extern "C" fn mmtk_register_ppp_with_reason(object: ObjectReference, reason: u32) {
+    crate::binding().ppp_registry.register(object, reason)
+}
+
+/// Get the number of PPPs registered with `reason`, and the number of their children pinned in the
+/// last moving GC.
+#[no_mangle]
+pub extern "C" fn mmtk_get_ppp_stats(reason: u32) -> PPPStats {
+    crate::binding().ppp_registry.get_stats(reason)
+}
+
+/// Register `object` as an object with finalizers.  When it dies, it will be resurrected and
+/// passed to the `enqueue_finalizer_jobs` upcall.  The VM should only call this when the object
+/// gets its first finalizer.
+#[no_mangle]
+pub extern "C" fn mmtk_add_finalizer(object: ObjectReference) {
+    memory_manager::add_finalizer(mmtk(), object)
+}
+
+/// Unregister `object` so that it will not be resurrected when it dies.  Called when all
+/// finalizers of the object are undefined.  Return false if it was not registered.
+#[no_mangle]
+pub extern "C" fn mmtk_remove_finalizer(object: ObjectReference) -> bool {
+    !memory_manager::get_finalizers_for(mmtk(), object).is_empty()
+}
+
+/// Unregister all objects with finalizers, and return them.  Used for running all finalizers when
+/// the VM exits.  The vector must be freed with `mmtk_free_raw_vec_of_obj_ref`.
+#[no_mangle]
+pub extern "C" fn mmtk_get_all_finalizers() -> RawVecOfObjRef {
+    RawVecOfObjRef::from_vec(memory_manager::get_all_finalizers(mmtk()))
 }
 
 #[no_mangle]
 pub extern "C" fn mmtk_register_ppps(objects: *const ObjectReference, len: usize) {
     let objects_slice = unsafe { std::slice::from_raw_parts(objects, len) };
-    crate::binding().ppp_registry.register_many(objects_slice)
+    crate::binding()
+        .ppp_registry
+        .register_many(objects_slice, abi::PPP_REASON_UNKNOWN)
+}
+
+#[no_mangle]
+pub extern "C" fn mmtk_register_ppps_with_reason(
+    objects: *const ObjectReference,
+    len: usize,
+    reason: u32,
+) {
+    let objects_slice = unsafe { std::slice::from_raw_parts(objects, len) };
+    crate::binding()
+        .ppp_registry
+        .register_many(objects_slice, reason)
+}
+
+/// Register a weak `st_table` so that it is processed in every GC.  Entries whose keys (if
+/// `weak_keys`) or values (if `weak_values`) are dead will be removed, and references in the
+/// remaining entries will be forwarded.  `name` is a '\0'-terminated string for logging and
+/// tracing.  The binding makes a copy of it.
+///
+/// Set `address_hashed` if keys are hashed by their addresses, in which case the table is rehashed
+/// after objects are moved.  Otherwise the bins are updated in parallel chunks.
+///
+/// Return false without registering the table if neither `weak_keys` nor `weak_values` is set.
+/// The table must remain valid until `mmtk_unregister_weak_table` is called with it.
+#[no_mangle]
+pub extern "C" fn mmtk_register_weak_table(
+    table: *mut st_table,
+    name: *const libc::c_char,
+    weak_keys: bool,
+    weak_values: bool,
+    address_hashed: bool,
+) -> bool {
+    let name_cstr = unsafe { CStr::from_ptr(name) };
+    let name_str = name_cstr.to_str().unwrap();
+    binding()
+        .weak_proc
+        .register_weak_table(table, name_str, weak_keys, weak_values, address_hashed)
+}
+
+/// Unregister a weak `st_table`.  Return false if the table was not registered.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_weak_table(table: *mut st_table) -> bool {
+    binding().weak_proc.unregister_weak_table(table)
+}
+
+/// Register a weak slot.  `slot` is the address of a `VALUE` which does not keep the object it
+/// refers to alive.  After each GC, if the object is dead, `cleared_value` will be written into
+/// `slot`; otherwise `slot` will be updated if the object is moved.
+///
+/// `slot` must not be a field of an object in the MMTk heap that may be moved.  It may be in
+/// memory freed by the `obj_free` of its owner, because weak slots are processed before `obj_free`
+/// is called on dead objects, but the owner must unregister it before freeing the memory.
+#[no_mangle]
+pub extern "C" fn mmtk_register_weak_slot(slot: Address, cleared_value: Address) {
+    binding().weak_slots.register(slot, cleared_value)
+}
+
+/// Unregister a weak slot.  Return false if the slot was not registered.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_weak_slot(slot: Address) -> bool {
+    binding().weak_slots.unregister(slot)
+}
+
+/// Register an ephemeron, i.e. a pair of `VALUE` slots where the object in `value_slot` is kept
+/// alive only if the object in `key_slot` is reachable.  When the key dies, `cleared_value` will
+/// be written into both slots.  Both slots are updated if the objects are moved.
+///
+/// Like weak slots, neither slot may be a field of an object in the MMTk heap that may be moved.
+#[no_mangle]
+pub extern "C" fn mmtk_register_ephemeron(
+    key_slot: Address,
+    value_slot: Address,
+    cleared_value: Address,
+) {
+    binding()
+        .weak_slots
+        .register_ephemeron(key_slot, value_slot, cleared_value)
+}
+
+/// Unregister the ephemeron with `key_slot`.  Return false if it was not registered.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_ephemeron(key_slot: Address) -> bool {
+    binding().weak_slots.unregister_ephemeron(key_slot)
 }
 
 #[no_mangle]
@@ -345,14 +551,67 @@ pub extern "C" fn mmtk_get_immix_bump_ptr_offset() -> usize {
     bump_pointer_offset
 }
 
+/// Add a pin to `object`.  Pins are reference-counted, and the object stays pinned until every
+/// pin is removed with `mmtk_unpin_object`.  If the current thread is in a pin scope, the pin is
+/// removed when the scope exits.  Return true if the object was not pinned before.
 #[no_mangle]
 pub extern "C" fn mmtk_pin_object(object: ObjectReference) -> bool {
-    mmtk::memory_manager::pin_object(object)
+    binding().pin_registry.pin(object)
 }
 
+/// Remove a pin from `object`.  Return true if the object is no longer pinned.
 #[no_mangle]
 pub extern "C" fn mmtk_unpin_object(object: ObjectReference) -> bool {
-    mmtk::memory_manager::unpin_object(object)
+    binding().pin_registry.unpin(object)
+}
+
+/// Return the number of pins of `object`.
+#[no_mangle]
+pub extern "C" fn mmtk_pin_count(object: ObjectReference) -> usize {
+    binding().pin_registry.pin_count(object)
+}
+
+/// Enter a pin scope.  Objects pinned with `mmtk_pin_object` on the current thread until the
+/// matching `mmtk_pin_scope_exit` are unpinned when the scope exits.  Scopes can be nested.
+#[no_mangle]
+pub extern "C" fn mmtk_pin_scope_enter() {
+    binding().pin_registry.enter_scope()
+}
+
+/// Exit the innermost pin scope of the current thread, and unpin the objects pinned in it.
+#[no_mangle]
+pub extern "C" fn mmtk_pin_scope_exit() {
+    binding().pin_registry.exit_scope()
+}
+
+/// Register a move listener.  After each moving GC, `callback` is called with batches of watched
+/// objects that have been moved, before mutators are resumed.  It must not allocate objects in
+/// the MMTk heap.  Return the ID of the listener, which is never zero.
+#[no_mangle]
+pub extern "C" fn mmtk_register_move_listener(
+    callback: MoveListenerCallback,
+    data: *mut libc::c_void,
+) -> usize {
+    binding().move_listeners.register(callback, data)
+}
+
+/// Unregister a move listener.  Return false if there is no listener with `id`.
+#[no_mangle]
+pub extern "C" fn mmtk_unregister_move_listener(id: usize) -> bool {
+    binding().move_listeners.unregister(id)
+}
+
+/// Let the move listener `id` watch `object`.  Watching does not keep the object alive.  Return
+/// false if there is no listener with `id`.
+#[no_mangle]
+pub extern "C" fn mmtk_move_listener_watch(id: usize, object: ObjectReference) -> bool {
+    binding().move_listeners.watch(id, object)
+}
+
+/// Let the move listener `id` stop watching `object`.  Return false if it was not watched.
+#[no_mangle]
+pub extern "C" fn mmtk_move_listener_unwatch(id: usize, object: ObjectReference) -> bool {
+    binding().move_listeners.unwatch(id, object)
 }
 
 #[no_mangle]
@@ -360,9 +619,19 @@ pub extern "C" fn mmtk_is_pinned(object: ObjectReference) -> bool {
     mmtk::memory_manager::is_pinned(object)
 }
 
+/// Register a WB-unprotected object.  Writes to it are not tracked, so it is scanned in every
+/// nursery GC.
 #[no_mangle]
 pub extern "C" fn mmtk_register_wb_unprotected_object(object: ObjectReference) {
-    crate::binding().register_wb_unprotected_object(object)
+    crate::binding().register_wb_unprotected_object(object, false)
+}
+
+/// Register a WB-unprotected object whose writes the VM reports with `mmtk_object_maybe_written`.
+/// Nursery GCs only scan it if it may have been written since it was last scanned, unless it is
+/// marked with `mmtk_mark_object_escaped`.
+#[no_mangle]
+pub extern "C" fn mmtk_register_write_reported_wb_unprotected_object(object: ObjectReference) {
+    crate::binding().register_wb_unprotected_object(object, true)
 }
 
 #[no_mangle]
@@ -370,6 +639,45 @@ pub extern "C" fn mmtk_is_object_wb_unprotected(object: ObjectReference) -> bool
     crate::binding().is_object_wb_unprotected(object)
 }
 
+/// Notify the binding that a WB-unprotected object may have been written, for example when native
+/// code gets a raw pointer to its fields.  Nursery GCs only scan objects registered with
+/// `mmtk_register_write_reported_wb_unprotected_object` if they may have been written since they
+/// were last scanned, so the VM must call this (or `mmtk_mark_object_escaped`) before or after
+/// every write to such objects.
+#[no_mangle]
+pub extern "C" fn mmtk_object_maybe_written(object: ObjectReference) {
+    crate::binding().object_maybe_written(object)
+}
+
+/// Notify the binding that native code may write to a WB-unprotected object at any time without
+/// calling `mmtk_object_maybe_written`.  The object will be scanned in every nursery GC.
+#[no_mangle]
+pub extern "C" fn mmtk_mark_object_escaped(object: ObjectReference) {
+    crate::binding().mark_object_escaped(object)
+}
+
+/// Query if the current plan needs the precise form of write barriers, i.e.
+/// `mmtk_object_reference_write_pre` and `mmtk_object_reference_write_post_precise` (or the
+/// combined `mmtk_object_reference_write`) with the real slot and target.
+///
+/// Plans that use the object-remembering barrier (`GenCopy`, `GenImmix` and `StickyImmix`) only
+/// need to know the source object, and `mmtk_object_reference_write_post` is enough.  Plans that
+/// do not use barriers (`NoGC`, `SemiSpace`, `Immix`, `MarkSweep`, etc.) need neither.  Any other
+/// barrier, such as field-logging barriers or snapshot-at-the-beginning (SATB) barriers, needs
+/// the slot and the target, and requires the precise form.
+#[no_mangle]
+pub extern "C" fn mmtk_needs_precise_write_barrier() -> bool {
+    !matches!(
+        mmtk().get_plan().constraints().barrier,
+        BarrierSelector::NoBarrier | BarrierSelector::ObjectBarrier
+    )
+}
+
+/// The object-remembering form of the post-write barrier.
+///
+/// It only tells MMTk that some field of `object` has been written, and passes neither the slot
+/// nor the target.  It is only sufficient for object-remembering barriers.  See
+/// `mmtk_needs_precise_write_barrier`.
 #[no_mangle]
 pub extern "C" fn mmtk_object_reference_write_post(
     mutator: *mut RubyMutator,
@@ -385,6 +693,82 @@ pub extern "C" fn mmtk_object_reference_write_post(
     )
 }
 
+/// The precise pre-write barrier.  Call it before storing `target` into `slot` which is a field
+/// of `src`.  `target` is a Ruby `VALUE`, and may be a special constant.
+#[no_mangle]
+pub extern "C" fn mmtk_object_reference_write_pre(
+    mutator: *mut RubyMutator,
+    src: ObjectReference,
+    slot: Address,
+    target: Address,
+) {
+    mmtk::memory_manager::object_reference_write_pre(
+        unsafe { &mut *mutator },
+        src,
+        RubySlot::from_address(slot),
+        value_to_objref(target),
+    )
+}
+
+/// The object-remembering form of the pre-write barrier.  Call it before modifying fields of
+/// `object` in bulk without per-field barriers, such as `memcpy` into an array, or before handing
+/// a raw pointer of the fields of `object` to C code.
+///
+/// Snapshot-at-the-beginning (SATB) barriers use it to record `object` so that its fields are
+/// scanned again before they are overwritten.
+///
+/// No slot is passed to mmtk-core, so this function is only valid for barriers that remember whole
+/// objects.  It panics if `mmtk_needs_precise_write_barrier` returns true.  Use
+/// `mmtk_object_reference_write_pre` for each field in that case.
+#[no_mangle]
+pub extern "C" fn mmtk_object_reference_write_pre_object(
+    mutator: *mut RubyMutator,
+    object: ObjectReference,
+) {
+    assert!(
+        !mmtk_needs_precise_write_barrier(),
+        "The current barrier logs individual fields.  Use mmtk_object_reference_write_pre instead."
+    );
+    let ignored_slot = RubySlot::from_address(Address::ZERO);
+    mmtk::memory_manager::object_reference_write_pre(
+        unsafe { &mut *mutator },
+        object,
+        ignored_slot,
+        None,
+    )
+}
+
+/// The precise post-write barrier.  Call it after storing `target` into `slot` which is a field
+/// of `src`.  `target` is a Ruby `VALUE`, and may be a special constant.
+#[no_mangle]
+pub extern "C" fn mmtk_object_reference_write_post_precise(
+    mutator: *mut RubyMutator,
+    src: ObjectReference,
+    slot: Address,
+    target: Address,
+) {
+    mmtk::memory_manager::object_reference_write_post(
+        unsafe { &mut *mutator },
+        src,
+        RubySlot::from_address(slot),
+        value_to_objref(target),
+    )
+}
+
+/// Store `target` into `slot` which is a field of `src`, with both the precise pre-write and
+/// post-write barriers applied.  `target` is a Ruby `VALUE`, and may be a special constant.
+#[no_mangle]
+pub extern "C" fn mmtk_object_reference_write(
+    mutator: *mut RubyMutator,
+    src: ObjectReference,
+    slot: Address,
+    target: Address,
+) {
+    mmtk_object_reference_write_pre(mutator, src, slot, target);
+    unsafe { slot.store::<Address>(target) };
+    mmtk_object_reference_write_post_precise(mutator, src, slot, target);
+}
+
 /// Enumerate objects.  This function will call `callback(object, data)` for each object. It has
 /// undefined behavior if allocation or GC happens while this function is running.
 #[no_mangle]
@@ -397,6 +781,13 @@ pub extern "C" fn mmtk_enumerate_objects(
     })
 }
 
+/// Return the size of `object`, including the hidden prefix and suffix.  During GC, this also
+/// works on the old copy of a forwarded object, and returns the size of the old copy.
+#[no_mangle]
+pub extern "C" fn mmtk_get_object_size(object: ObjectReference) -> usize {
+    RubyObjectAccess::from_objref(object).object_size_maybe_forwarded()
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_hidden_header_is_sane(hidden_header: *const HiddenHeader) -> bool {
     let hidden_header = unsafe { &*hidden_header };
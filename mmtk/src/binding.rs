@@ -1,7 +1,6 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::ffi::CString;
-use std::str::FromStr;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::thread::JoinHandle;
 
@@ -11,12 +10,22 @@ use mmtk::MMTK;
 
 use crate::abi;
 use crate::abi::RubyBindingOptions;
+use crate::dead_objects::DeadObjectTracker;
+use crate::move_listeners::MoveListenerRegistry;
+use crate::object_model::ResizedObjects;
+use crate::options::TuningOptions;
+use crate::pinning::PinRegistry;
 use crate::ppp::PPPRegistry;
+use crate::utils::HeapBitmap;
 use crate::weak_proc::WeakProcessor;
+use crate::weak_slots::WeakSlotRegistry;
 use crate::Ruby;
 
 pub struct RubyBindingFast {
     pub gc_enabled: AtomicBool,
+    /// True from `stop_all_mutators` to `resume_mutators`.  Objects can only be forwarded in this
+    /// period, and forwarding bits are not meaningful outside it.
+    pub world_stopped: AtomicBool,
 }
 
 impl Default for RubyBindingFast {
@@ -29,12 +38,16 @@ impl RubyBindingFast {
     pub const fn new() -> Self {
         Self {
             gc_enabled: AtomicBool::new(false),
+            world_stopped: AtomicBool::new(false),
         }
     }
 }
 
 pub struct RubyBindingFastMut {
     pub suffix_size: usize,
+    /// Copied from `RubyBindingOptions::after_copy_types`, or zero if there is no `after_copy`
+    /// upcall.
+    pub after_copy_types: u32,
 }
 
 impl Default for RubyBindingFastMut {
@@ -45,7 +58,10 @@ impl Default for RubyBindingFastMut {
 
 impl RubyBindingFastMut {
     pub const fn new() -> Self {
-        Self { suffix_size: 0 }
+        Self {
+            suffix_size: 0,
+            after_copy_types: 0,
+        }
     }
 }
 
@@ -61,26 +77,35 @@ pub struct RubyBinding {
     pub plan_name: Mutex<Option<CString>>,
     pub weak_proc: WeakProcessor,
     pub ppp_registry: PPPRegistry,
+    pub pin_registry: PinRegistry,
+    pub move_listeners: MoveListenerRegistry,
+    pub weak_slots: WeakSlotRegistry,
+    pub dead_object_tracker: DeadObjectTracker,
     pub(crate) moved_givtbl: Mutex<HashMap<ObjectReference, MovedGIVTblEntry>>,
+    /// The old payload sizes of objects resized when copied in the current GC, keyed by their old
+    /// copies.  Cleared when mutators are resumed.
+    pub resized_objects: ResizedObjects,
     pub gc_thread_join_handles: Mutex<Vec<JoinHandle<()>>>,
-    pub wb_unprotected_objects: Mutex<HashSet<ObjectReference>>,
-    pub st_entries_chunk_size: usize,
-    pub st_bins_chunk_size: usize,
+    /// One bit per object.  Set if the object is WB-unprotected.
+    pub wb_unprotected_bits: HeapBitmap,
+    /// Set if a WB-unprotected object may have been written since it was last scanned.
+    pub wb_unprotected_dirty_bits: HeapBitmap,
+    /// Set if a WB-unprotected object has escaped to native code which may write to it at any
+    /// time, or if the VM does not report writes to it.  Never cleared while the object is alive.
+    pub wb_unprotected_escaped_bits: HeapBitmap,
+    /// WB-unprotected objects that have survived at least one GC.  Nursery GCs scan them as roots.
+    pub wb_unprotected_objects: Mutex<Vec<ObjectReference>>,
+    /// WB-unprotected objects registered since the last GC.  Nursery GCs also scan the ones that
+    /// were already old when registered as roots.
+    pub young_wb_unprotected_objects: Mutex<Vec<ObjectReference>>,
+    pub tuning: TuningOptions,
+    /// If true, the next stack scan must scan all frames even if return barriers are enabled.
+    force_full_stack_scan: AtomicBool,
 }
 
 unsafe impl Sync for RubyBinding {}
 unsafe impl Send for RubyBinding {}
 
-fn env_default<T>(name: &str, default: T) -> T
-where
-    T: FromStr,
-{
-    std::env::var(name)
-        .ok()
-        .and_then(|x| x.parse::<T>().ok())
-        .unwrap_or(default)
-}
-
 impl RubyBinding {
     pub fn new(
         mmtk: &'static MMTK<Ruby>,
@@ -88,14 +113,30 @@ impl RubyBinding {
         upcalls: *const abi::RubyUpcalls,
     ) -> Self {
         unsafe {
+            assert!(
+                !binding_options.return_barrier
+                    || (*upcalls).scan_roots_in_mutator_thread_partially.is_some(),
+                "scan_roots_in_mutator_thread_partially is required if return_barrier is enabled."
+            );
+            assert!(
+                (*upcalls).get_optimal_payload_size.is_none() || (*upcalls).after_copy.is_some(),
+                "The after_copy upcall is required if get_optimal_payload_size is provided."
+            );
+            #[cfg(feature = "no_hidden_header")]
+            assert!(
+                (*upcalls).get_payload_size.is_some(),
+                "The get_payload_size upcall is required with the no_hidden_header feature."
+            );
             crate::BINDING_FAST_MUT.suffix_size = binding_options.suffix_size;
+            crate::BINDING_FAST_MUT.after_copy_types = if (*upcalls).after_copy.is_some() {
+                binding_options.after_copy_types
+            } else {
+                0
+            };
         }
 
-        let st_entries_chunk_size = env_default::<usize>("RUBY_MMTK_ENTRIES_CHUNK_SIZE", 1024);
-        let st_bins_chunk_size = env_default::<usize>("RUBY_MMTK_BINS_CHUNK_SIZE", 4096);
-
-        debug!("st_entries_chunk_size: {st_entries_chunk_size}");
-        debug!("st_bins_chunk_size: {st_bins_chunk_size}");
+        let tuning = TuningOptions::from_env_and_builder();
+        tuning.report();
 
         Self {
             mmtk,
@@ -104,11 +145,20 @@ impl RubyBinding {
             plan_name: Mutex::new(None),
             weak_proc: WeakProcessor::new(),
             ppp_registry: PPPRegistry::new(),
+            pin_registry: PinRegistry::new(),
+            move_listeners: MoveListenerRegistry::new(),
+            weak_slots: WeakSlotRegistry::new(),
+            dead_object_tracker: DeadObjectTracker::new(),
             moved_givtbl: Default::default(),
+            resized_objects: Default::default(),
             gc_thread_join_handles: Default::default(),
+            wb_unprotected_bits: HeapBitmap::new(),
+            wb_unprotected_dirty_bits: HeapBitmap::new(),
+            wb_unprotected_escaped_bits: HeapBitmap::new(),
             wb_unprotected_objects: Default::default(),
-            st_entries_chunk_size,
-            st_bins_chunk_size,
+            young_wb_unprotected_objects: Default::default(),
+            tuning,
+            force_full_stack_scan: AtomicBool::new(false),
         }
     }
 
@@ -143,14 +193,63 @@ impl RubyBinding {
         }
     }
 
-    pub fn register_wb_unprotected_object(&self, object: ObjectReference) {
-        debug!("Registering WB-unprotected object: {}", object);
-        let mut objects = self.wb_unprotected_objects.lock().unwrap();
-        objects.insert(object);
+    /// Decide whether the stacks of mutators can be scanned partially in the current GC.
+    ///
+    /// Frames below the return barrier have not changed since the last stack scan, and the
+    /// objects they refer to have survived that GC.  In a nursery GC, those objects are mature and
+    /// will not be moved, so we don't need to visit those frames again.  All other GCs need to scan
+    /// all frames.
+    pub fn can_scan_stacks_partially(&self) -> bool {
+        self.options.return_barrier
+            && !self.force_full_stack_scan.load(Ordering::Relaxed)
+            && (self.mmtk.get_plan().generational()).is_some_and(|gen| gen.is_current_gc_nursery())
+    }
+
+    /// Force all subsequent stack scans in the current GC to scan all frames.
+    pub fn force_full_stack_scan(&self) {
+        self.force_full_stack_scan.store(true, Ordering::Relaxed);
+    }
+
+    /// Called at the end of each GC.  Allow partial stack scanning again.
+    pub fn reset_stack_scan_mode(&self) {
+        self.force_full_stack_scan.store(false, Ordering::Relaxed);
+    }
+
+    /// Register a WB-unprotected object.  If `writes_reported` is false, the VM does not report
+    /// writes to it with `object_maybe_written`, so it is treated as escaped and scanned in every
+    /// nursery GC.
+    pub fn register_wb_unprotected_object(&self, object: ObjectReference, writes_reported: bool) {
+        debug!("Registering WB-unprotected object: {object}, writes_reported: {writes_reported}");
+        // The VM may have written to it without notifying us before it is registered.
+        self.wb_unprotected_dirty_bits.set(object);
+        if !writes_reported {
+            self.wb_unprotected_escaped_bits.set(object);
+        }
+        if self.wb_unprotected_bits.set(object) {
+            let mut objects = self.young_wb_unprotected_objects.lock().unwrap();
+            objects.push(object);
+        }
     }
 
     pub fn is_object_wb_unprotected(&self, object: ObjectReference) -> bool {
-        let objects = self.wb_unprotected_objects.lock().unwrap();
-        objects.contains(&object)
+        self.wb_unprotected_bits.is_set(object)
+    }
+
+    /// Record that a WB-unprotected object may have been written.
+    pub fn object_maybe_written(&self, object: ObjectReference) {
+        self.wb_unprotected_dirty_bits.set(object);
+    }
+
+    /// Record that a WB-unprotected object has escaped to native code.  It will be scanned in
+    /// every nursery GC.
+    pub fn mark_object_escaped(&self, object: ObjectReference) {
+        self.wb_unprotected_escaped_bits.set(object);
+    }
+
+    /// Return true if a WB-unprotected object needs to be scanned in a nursery GC, and clear its
+    /// dirty bit.  Called when generating root-scanning packets.
+    pub fn take_wb_unprotected_object_dirty(&self, object: ObjectReference) -> bool {
+        let dirty = self.wb_unprotected_dirty_bits.clear(object);
+        dirty || self.wb_unprotected_escaped_bits.is_set(object)
     }
 }
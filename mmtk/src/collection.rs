@@ -21,6 +21,9 @@ impl Collection<Ruby> for VMCollection {
         F: FnMut(&'static mut mmtk::Mutator<Ruby>),
     {
         (upcalls().stop_the_world)(tls);
+        crate::BINDING_FAST
+            .world_stopped
+            .store(true, Ordering::SeqCst);
         crate::binding().ppp_registry.pin_ppp_children(tls);
         (upcalls().get_mutators)(
             Self::notify_mutator_ready::<F>,
@@ -29,6 +32,14 @@ impl Collection<Ruby> for VMCollection {
     }
 
     fn resume_mutators(tls: VMWorkerThread) {
+        crate::BINDING_FAST
+            .world_stopped
+            .store(false, Ordering::SeqCst);
+        crate::binding().reset_stack_scan_mode();
+        crate::binding().resized_objects.clear();
+        Self::enqueue_finalizer_jobs();
+        crate::binding().dead_object_tracker.report_dead_objects();
+        crate::binding().move_listeners.report_moves();
         (upcalls().resume_mutators)(tls);
     }
 
@@ -77,6 +88,17 @@ impl Collection<Ruby> for VMCollection {
 }
 
 impl VMCollection {
+    /// Hand objects resurrected by mmtk-core's finalization to the VM.
+    fn enqueue_finalizer_jobs() {
+        let objects =
+            std::iter::from_fn(|| memory_manager::get_finalized_object(mmtk())).collect::<Vec<_>>();
+        if objects.is_empty() {
+            return;
+        }
+        debug!("Enqueuing finalizer jobs for {} objects", objects.len());
+        (upcalls().enqueue_finalizer_jobs)(objects.as_ptr(), objects.len());
+    }
+
     extern "C" fn notify_mutator_ready<F>(mutator_ptr: *mut RubyMutator, data: *mut libc::c_void)
     where
         F: FnMut(&'static mut mmtk::Mutator<Ruby>),
@@ -0,0 +1,230 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use mmtk::{
+    scheduler::{GCWork, GCWorker, WorkBucketStage},
+    util::ObjectReference,
+    MMTK,
+};
+
+use crate::utils::{PerThreadBuffers, ThreadBuffer};
+use crate::Ruby;
+
+/// The callback that receives dead objects after each GC.  `young` is true if the objects died
+/// before surviving any GC.  The objects have been reclaimed, so only their addresses are
+/// meaningful.  The callback is called by a GC thread before mutators are resumed, so it must
+/// not allocate objects in the MMTk heap.
+pub type DeadObjectCallback = extern "C" fn(
+    objects: *const ObjectReference,
+    len: usize,
+    young: bool,
+    data: *mut libc::c_void,
+);
+
+struct RegisteredCallback {
+    callback: DeadObjectCallback,
+    data: *mut libc::c_void,
+}
+
+unsafe impl Send for RegisteredCallback {}
+
+thread_local! {
+    /// The number of `track_object` calls on the current thread, for sampling.
+    static TRACK_COUNTER: Cell<usize> = const { Cell::new(0) };
+
+    /// The tracked object buffer of the current thread.  It is registered to the
+    /// `DeadObjectTracker` when first used.
+    static TRACKED_OBJECT_BUFFER: Arc<ThreadBuffer<ObjectReference>> =
+        crate::binding().dead_object_tracker.buffers.register();
+}
+
+/// Reports dead objects to a registered callback, for `RUBY_INTERNAL_EVENT_FREEOBJ` and memory
+/// profilers.
+///
+/// MMTk doesn't visit dead objects, so the VM has to call `track_object` for newly allocated
+/// objects while tracking is enabled.  Tracked objects are checked after the transitive closure of
+/// each GC, and dead ones are reported in `resume_mutators`.  In nursery GCs, only objects that
+/// have not survived any GC are checked, because older objects can't die.
+pub struct DeadObjectTracker {
+    enabled: AtomicBool,
+    /// Track one object in every `sample_period` calls of `track_object` on each thread.
+    sample_period: AtomicUsize,
+    callback: Mutex<Option<RegisteredCallback>>,
+    /// Per-thread buffers of newly sampled objects.
+    buffers: PerThreadBuffers<ObjectReference>,
+    /// Tracked objects that have not survived any GC, other than those in per-thread buffers.
+    young_objects: Mutex<Vec<ObjectReference>>,
+    /// Tracked objects that have survived at least one GC.
+    old_objects: Mutex<Vec<ObjectReference>>,
+    /// Objects found dead in the current GC, to be reported.  The first vector holds young
+    /// objects, and the second holds old objects.
+    dead_objects: Mutex<(Vec<ObjectReference>, Vec<ObjectReference>)>,
+}
+
+impl DeadObjectTracker {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            sample_period: AtomicUsize::new(1),
+            callback: Default::default(),
+            buffers: Default::default(),
+            young_objects: Default::default(),
+            old_objects: Default::default(),
+            dead_objects: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Start tracking objects, and report dead ones to `callback`.  A `sample_period` of `n`
+    /// tracks one in every `n` objects.  Replaces the previous callback, if any.
+    pub fn register_callback(
+        &self,
+        callback: DeadObjectCallback,
+        data: *mut libc::c_void,
+        sample_period: usize,
+    ) {
+        assert!(sample_period > 0, "sample_period must be positive");
+        *self.callback.lock().unwrap() = Some(RegisteredCallback { callback, data });
+        self.sample_period.store(sample_period, Ordering::Relaxed);
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop tracking objects, and forget all tracked objects.  Objects in the per-thread buffers of
+    /// other threads are discarded in the next GC unless tracking is enabled again before that.
+    /// Return false if no callback was registered.
+    pub fn unregister_callback(&self) -> bool {
+        self.enabled.store(false, Ordering::SeqCst);
+        self.young_objects.lock().unwrap().clear();
+        self.old_objects.lock().unwrap().clear();
+        self.callback.lock().unwrap().take().is_some()
+    }
+
+    /// Called by the VM for each newly allocated object while tracking is enabled.
+    pub fn track_object(&self, object: ObjectReference) {
+        if !self.is_enabled() {
+            return;
+        }
+        let sample_period = self.sample_period.load(Ordering::Relaxed);
+        let sampled = TRACK_COUNTER.with(|counter| {
+            let count = counter.get() + 1;
+            if count >= sample_period {
+                counter.set(0);
+                true
+            } else {
+                counter.set(count);
+                false
+            }
+        });
+        if sampled {
+            let result =
+                ThreadBuffer::with_current(&TRACKED_OBJECT_BUFFER, |buffer| buffer.push(object));
+            if result.is_err() {
+                // The thread-local buffer has been destroyed because the thread is exiting.  This
+                // is rare, so we just take the lock.
+                self.young_objects.lock().unwrap().push(object);
+            }
+        }
+    }
+
+    /// Move objects in per-thread buffers to `young_objects`.  Mutators must not be tracking
+    /// objects concurrently.
+    fn flush_buffers(&self, young_objects: &mut Vec<ObjectReference>) {
+        for mut objects in self.buffers.take_all() {
+            young_objects.append(&mut objects);
+        }
+    }
+
+    /// Schedule a work packet to find dead tracked objects.  Called after the transitive closure.
+    pub fn process_tracked_objects(&self, worker: &mut GCWorker<Ruby>) {
+        if !self.is_enabled() {
+            // Discard objects buffered before tracking was disabled.
+            let mut discarded = vec![];
+            self.flush_buffers(&mut discarded);
+            return;
+        }
+        worker.add_work(WorkBucketStage::VMRefClosure, ProcessTrackedObjects);
+    }
+
+    /// Report dead objects found in the current GC to the callback.  Called before mutators are
+    /// resumed.
+    pub fn report_dead_objects(&self) {
+        let (dead_young, dead_old) = std::mem::take(&mut *self.dead_objects.lock().unwrap());
+        // Don't hold the lock while calling the callback.  It may register or unregister itself.
+        let Some((callback, data)) = self
+            .callback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|registered| (registered.callback, registered.data))
+        else {
+            return;
+        };
+        for (objects, young) in [(dead_young, true), (dead_old, false)] {
+            if !objects.is_empty() {
+                debug!("Reporting {} dead objects, young: {young}", objects.len());
+                callback(objects.as_ptr(), objects.len(), young, data);
+            }
+        }
+    }
+}
+
+impl Default for DeadObjectTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Remove dead objects from `objects` and return them.  Forward the remaining objects.
+fn sweep_tracked_objects(objects: &mut Vec<ObjectReference>) -> Vec<ObjectReference> {
+    let mut dead = vec![];
+    objects.retain_mut(|object| {
+        if object.is_reachable() {
+            if let Some(new_object) = object.get_forwarded_object() {
+                *object = new_object;
+            }
+            true
+        } else {
+            dead.push(*object);
+            false
+        }
+    });
+    dead
+}
+
+struct ProcessTrackedObjects;
+
+impl GCWork<Ruby> for ProcessTrackedObjects {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, mmtk: &'static MMTK<Ruby>) {
+        let tracker = &crate::binding().dead_object_tracker;
+        let is_nursery_gc =
+            (mmtk.get_plan().generational()).is_some_and(|gen| gen.is_current_gc_nursery());
+
+        let mut young_objects = tracker
+            .young_objects
+            .try_lock()
+            .expect("Mutators should not be tracking objects during GC.");
+        tracker.flush_buffers(&mut young_objects);
+        let dead_young = sweep_tracked_objects(&mut young_objects);
+
+        let mut old_objects = tracker.old_objects.try_lock().unwrap();
+        let dead_old = if is_nursery_gc {
+            vec![]
+        } else {
+            sweep_tracked_objects(&mut old_objects)
+        };
+        // Surviving young objects become old.
+        old_objects.append(&mut young_objects);
+
+        debug!(
+            "Tracked objects: {} young and {} old objects died, {} remaining",
+            dead_young.len(),
+            dead_old.len(),
+            old_objects.len()
+        );
+        *tracker.dead_objects.lock().unwrap() = (dead_young, dead_old);
+    }
+}
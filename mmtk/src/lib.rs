@@ -23,12 +23,17 @@ pub mod active_plan;
 pub mod api;
 pub mod binding;
 pub mod collection;
+pub mod dead_objects;
+pub mod move_listeners;
 pub mod object_model;
+pub mod options;
+pub mod pinning;
 pub mod ppp;
 pub mod reference_glue;
 pub mod scanning;
 pub mod utils;
 pub mod weak_proc;
+pub mod weak_slots;
 
 #[derive(Default)]
 pub struct Ruby;
@@ -52,6 +57,8 @@ impl VMBinding for Ruby {
 
     type VMSlot = RubySlot;
     type VMMemorySlice = RubyMemorySlice;
+
+    const MAX_ALIGNMENT: usize = abi::MAX_OBJ_ALIGN;
 }
 
 /// The singleton object for the Ruby binding itself.
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use mmtk::{
+    scheduler::{GCWork, GCWorker, WorkBucketStage},
+    util::ObjectReference,
+    MMTK,
+};
+
+use crate::{abi::MovedObject, Ruby};
+
+/// Called after a moving GC with a batch of watched objects that have been moved.
+pub type MoveListenerCallback =
+    extern "C" fn(moves: *const MovedObject, len: usize, data: *mut libc::c_void);
+
+struct MoveListener {
+    callback: MoveListenerCallback,
+    data: *mut libc::c_void,
+    /// Objects watched by this listener, at their current addresses.
+    objects: HashSet<ObjectReference>,
+    /// Objects moved in the current GC, to be reported.
+    moves: Vec<MovedObject>,
+}
+
+unsafe impl Send for MoveListener {}
+
+/// Lets native extensions learn the new addresses of objects they cache, as an alternative to
+/// implementing `dcompact` or becoming PPPs.
+///
+/// Watching an object doesn't keep it alive.  Dead objects are silently removed from the watched
+/// set.  Moves are found from forwarding pointers in `VMRefClosure`, and are reported in batches
+/// before mutators are resumed.
+pub struct MoveListenerRegistry {
+    listeners: Mutex<HashMap<usize, Arc<Mutex<MoveListener>>>>,
+    next_id: Mutex<usize>,
+}
+
+impl MoveListenerRegistry {
+    /// The maximum number of moves passed to a callback at a time.
+    const MOVES_PER_BATCH: usize = 4096;
+
+    pub fn new() -> Self {
+        Self {
+            listeners: Default::default(),
+            next_id: Mutex::new(1),
+        }
+    }
+
+    /// Register a listener and return its ID.  IDs are never zero.
+    pub fn register(&self, callback: MoveListenerCallback, data: *mut libc::c_void) -> usize {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+        let listener = MoveListener {
+            callback,
+            data,
+            objects: Default::default(),
+            moves: vec![],
+        };
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.insert(id, Arc::new(Mutex::new(listener)));
+        id
+    }
+
+    /// Unregister a listener.  Return false if there is no listener with `id`.
+    pub fn unregister(&self, id: usize) -> bool {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.remove(&id).is_some()
+    }
+
+    fn with_listener<R>(&self, id: usize, f: impl FnOnce(&mut MoveListener) -> R) -> Option<R> {
+        let listener = self.listeners.lock().unwrap().get(&id)?.clone();
+        let mut listener = listener.lock().unwrap();
+        Some(f(&mut listener))
+    }
+
+    /// Let the listener `id` watch `object`.  Return false if there is no listener with `id`.
+    pub fn watch(&self, id: usize, object: ObjectReference) -> bool {
+        self.with_listener(id, |listener| {
+            listener.objects.insert(object);
+        })
+        .is_some()
+    }
+
+    /// Let the listener `id` stop watching `object`.  Return false if it was not watched.
+    pub fn unwatch(&self, id: usize, object: ObjectReference) -> bool {
+        self.with_listener(id, |listener| listener.objects.remove(&object))
+            .unwrap_or(false)
+    }
+
+    /// Schedule one work packet per listener to remove dead objects and find moved objects.
+    /// Called after the transitive closure.  Dead objects must be removed even in non-moving GCs,
+    /// or their memory may be reused by new objects which would later be reported as moved.
+    pub fn process_watched_objects(&self, worker: &mut GCWorker<Ruby>) {
+        let may_move = crate::mmtk().get_plan().current_gc_may_move_object();
+        let listeners = self
+            .listeners
+            .try_lock()
+            .expect("Mutators should not be registering move listeners during GC.");
+        let packets = listeners
+            .values()
+            .map(|listener| {
+                Box::new(FindMovedObjects {
+                    listener: listener.clone(),
+                    may_move,
+                }) as _
+            })
+            .collect::<Vec<_>>();
+        worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(packets);
+    }
+
+    /// Report moves found in the current GC.  Called before mutators are resumed.
+    pub fn report_moves(&self) {
+        // Don't hold locks while calling callbacks.  They may watch or unwatch objects.
+        let reports = {
+            let listeners = self.listeners.lock().unwrap();
+            listeners
+                .values()
+                .filter_map(|listener| {
+                    let mut listener = listener.lock().unwrap();
+                    let moves = std::mem::take(&mut listener.moves);
+                    (!moves.is_empty()).then_some((listener.callback, listener.data, moves))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (callback, data, moves) in reports {
+            for batch in moves.chunks(Self::MOVES_PER_BATCH) {
+                callback(batch.as_ptr(), batch.len(), data);
+            }
+        }
+    }
+}
+
+impl Default for MoveListenerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct FindMovedObjects {
+    listener: Arc<Mutex<MoveListener>>,
+    /// False if the current GC is non-moving, in which case we only remove dead objects.
+    may_move: bool,
+}
+
+impl GCWork<Ruby> for FindMovedObjects {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+        let mut listener = self.listener.lock().unwrap();
+
+        if !self.may_move {
+            let num_watched = listener.objects.len();
+            listener.objects.retain(|object| object.is_reachable());
+            debug!(
+                "Move listener: {num_watched} watched, {} dead, non-moving GC",
+                num_watched - listener.objects.len()
+            );
+            return;
+        }
+
+        let old_objects = std::mem::take(&mut listener.objects);
+        let num_watched = old_objects.len();
+        let mut num_dead = 0usize;
+
+        for object in old_objects {
+            if !object.is_reachable() {
+                trace!("Watched object died: {object}");
+                num_dead += 1;
+                continue;
+            }
+            let new_object = object.get_forwarded_object().unwrap_or(object);
+            if new_object != object {
+                trace!("Watched object moved: {object} -> {new_object}");
+                listener.moves.push(MovedObject {
+                    old: object,
+                    new: new_object,
+                });
+            }
+            listener.objects.insert(new_object);
+        }
+
+        debug!(
+            "Move listener: {num_watched} watched, {} moved, {num_dead} dead",
+            listener.moves.len()
+        );
+    }
+}
@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::ptr::copy_nonoverlapping;
+use std::sync::{Arc, Mutex};
 
-use crate::abi::{RubyObjectAccess, MIN_OBJ_ALIGN, OBJREF_OFFSET};
+use crate::abi::{RubyObjectAccess, MAX_OBJ_ALIGN, MIN_OBJ_ALIGN, OBJREF_OFFSET};
 use crate::{abi, Ruby};
+#[cfg(not(feature = "no_hidden_header"))]
 use mmtk::util::constants::BITS_IN_BYTE;
 use mmtk::util::copy::{CopySemantics, GCWorkerCopyContext};
 use mmtk::util::{Address, ObjectReference};
@@ -9,17 +12,96 @@ use mmtk::vm::*;
 
 pub struct VMObjectModel {}
 
+type ResizedObjectsBuffer = Arc<Mutex<Vec<(ObjectReference, usize)>>>;
+
+thread_local! {
+    /// The resized objects recorded by the current GC worker.
+    static RESIZED_OBJECTS_BUFFER: ResizedObjectsBuffer =
+        crate::binding().resized_objects.register_buffer();
+}
+
+/// The old payload sizes of objects resized when copied in the current GC, keyed by their old
+/// copies, for heap walkers.
+///
+/// Each GC worker records sizes in its own buffer, so copying never contends on a global lock.
+/// Buffers are only locked by other threads when merged into a map on lookup, which is rare.
+#[derive(Default)]
+pub struct ResizedObjects {
+    buffers: Mutex<Vec<ResizedObjectsBuffer>>,
+    merged: Mutex<HashMap<ObjectReference, usize>>,
+}
+
+impl ResizedObjects {
+    fn register_buffer(&self) -> ResizedObjectsBuffer {
+        let buffer = ResizedObjectsBuffer::default();
+        self.buffers.lock().unwrap().push(buffer.clone());
+        buffer
+    }
+
+    fn record(&self, object: ObjectReference, old_payload_size: usize) {
+        RESIZED_OBJECTS_BUFFER.with(|buffer| {
+            buffer.lock().unwrap().push((object, old_payload_size));
+        });
+    }
+
+    /// Return the old payload size of `object` if it was resized when copied in the current GC.
+    pub fn get(&self, object: ObjectReference) -> Option<usize> {
+        let mut merged = self.merged.lock().unwrap();
+        for buffer in self.buffers.lock().unwrap().iter() {
+            merged.extend(buffer.lock().unwrap().drain(..));
+        }
+        merged.get(&object).copied()
+    }
+
+    /// Forget all resized objects.  Called when mutators are resumed.
+    pub fn clear(&self) {
+        self.merged.lock().unwrap().clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        for buffer in buffers.iter() {
+            buffer.lock().unwrap().clear();
+        }
+        // Buffers only referenced from here belong to exited threads.
+        buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+    }
+}
+
 impl VMObjectModel {
     const OBJREF_OFFSET: usize = abi::OBJREF_OFFSET;
+
+    /// The payload size of the object after it is copied.  It is the current payload size unless
+    /// the VM provides the `get_optimal_payload_size` upcall.
+    fn payload_size_when_copied(object: ObjectReference) -> usize {
+        let Some(get_optimal_payload_size) = crate::upcalls().get_optimal_payload_size else {
+            return RubyObjectAccess::from_objref(object).payload_size();
+        };
+        let payload_size = get_optimal_payload_size(object);
+        debug_assert!(
+            payload_size % MIN_OBJ_ALIGN == 0,
+            "Payload size {} of object {} is not aligned",
+            payload_size,
+            object
+        );
+        payload_size
+    }
+
+    fn object_size_with_payload(payload_size: usize) -> usize {
+        RubyObjectAccess::prefix_size() + payload_size + RubyObjectAccess::suffix_size()
+    }
 }
 
 impl ObjectModel<Ruby> for VMObjectModel {
     const GLOBAL_LOG_BIT_SPEC: VMGlobalLogBitSpec = VMGlobalLogBitSpec::side_first();
 
     // We overwrite the prepended word which were used to hold object sizes.
+    #[cfg(not(feature = "no_hidden_header"))]
     const LOCAL_FORWARDING_POINTER_SPEC: VMLocalForwardingPointerSpec =
         VMLocalForwardingPointerSpec::in_header(-((OBJREF_OFFSET * BITS_IN_BYTE) as isize));
 
+    // Without the hidden header, we overwrite the first word of the payload, i.e. the flags.
+    #[cfg(feature = "no_hidden_header")]
+    const LOCAL_FORWARDING_POINTER_SPEC: VMLocalForwardingPointerSpec =
+        VMLocalForwardingPointerSpec::in_header(0);
+
     const LOCAL_FORWARDING_BITS_SPEC: VMLocalForwardingBitsSpec =
         VMLocalForwardingBitsSpec::side_first();
 
@@ -32,7 +114,8 @@ impl ObjectModel<Ruby> for VMObjectModel {
     const LOCAL_LOS_MARK_NURSERY_SPEC: VMLocalLOSMarkNurserySpec =
         VMLocalLOSMarkNurserySpec::side_after(Self::LOCAL_PINNING_BIT_SPEC.as_spec());
 
-    const UNIFIED_OBJECT_REFERENCE_ADDRESS: bool = false;
+    // Without the hidden header, an object reference points to the start of the object.
+    const UNIFIED_OBJECT_REFERENCE_ADDRESS: bool = Self::OBJREF_OFFSET == 0;
     const OBJECT_REF_OFFSET_LOWER_BOUND: isize = Self::OBJREF_OFFSET as isize;
 
     const NEED_VO_BITS_DURING_TRACING: bool = true;
@@ -50,15 +133,72 @@ impl ObjectModel<Ruby> for VMObjectModel {
         });
         let from_start = from_acc.obj_start();
         let object_size = from_acc.object_size();
-        let to_start = copy_context.alloc_copy(from, object_size, MIN_OBJ_ALIGN, 0, semantics);
+        let old_payload_size = from_acc.payload_size();
+        let new_payload_size = Self::payload_size_when_copied(from);
+        let new_object_size = Self::object_size_with_payload(new_payload_size);
+        let align = Self::get_align_when_copied(from);
+        let offset = Self::get_align_offset_when_copied(from);
+        let to_start = copy_context.alloc_copy(from, new_object_size, align, offset, semantics);
         debug_assert!(!to_start.is_zero());
         let to_payload = to_start.add(OBJREF_OFFSET);
-        unsafe {
-            copy_nonoverlapping::<u8>(from_start.to_ptr(), to_start.to_mut_ptr(), object_size);
-        }
+        debug_assert!(to_payload.is_aligned_to(align));
         // unsafe: `to_payload`` cannot be zero because `alloc_copy`` never returns zero.
         let to_obj = unsafe { ObjectReference::from_raw_address_unchecked(to_payload) };
-        copy_context.post_copy(to_obj, object_size, semantics);
+        let resized = new_payload_size != old_payload_size;
+        if !resized {
+            unsafe {
+                copy_nonoverlapping::<u8>(from_start.to_ptr(), to_start.to_mut_ptr(), object_size);
+            }
+        } else {
+            trace!(
+                "Resizing object {} from {} to {} bytes",
+                from,
+                old_payload_size,
+                new_payload_size
+            );
+            // Remember the old size so that heap walkers can still find the extent of the old copy
+            // after its header is overwritten by the forwarding pointer.
+            crate::binding()
+                .resized_objects
+                .record(from, old_payload_size);
+            // Copy the hidden header and the common part of the payload, and fix the size in the
+            // hidden header.  The suffix is moved to the end of the new payload.
+            let to_acc = RubyObjectAccess::from_objref(to_obj);
+            let common_size = old_payload_size.min(new_payload_size);
+            unsafe {
+                copy_nonoverlapping::<u8>(
+                    from_start.to_ptr(),
+                    to_start.to_mut_ptr(),
+                    RubyObjectAccess::prefix_size() + common_size,
+                );
+                if new_payload_size > common_size {
+                    // The VM will fill it in `after_copy`.
+                    std::ptr::write_bytes::<u8>(
+                        to_payload.add(common_size).to_mut_ptr(),
+                        0,
+                        new_payload_size - common_size,
+                    );
+                }
+            }
+            to_acc.set_payload_size(new_payload_size);
+            unsafe {
+                copy_nonoverlapping::<u8>(
+                    from_acc.suffix_addr().to_ptr(),
+                    to_payload.add(new_payload_size).to_mut_ptr(),
+                    RubyObjectAccess::suffix_size(),
+                );
+            }
+        }
+        // Call `after_copy` before `post_copy`.  Without the hidden header, the size of a resized
+        // object is only correct after the VM fixes it in `after_copy`.
+        let after_copy_types = unsafe { crate::BINDING_FAST_MUT.after_copy_types };
+        if resized || (after_copy_types >> from_acc.builtin_type()) & 1 != 0 {
+            // `after_copy_types` is non-zero only if the upcall is present, and objects are only
+            // resized if the upcall is present.
+            (crate::upcalls().after_copy.unwrap())(from, to_obj);
+        }
+
+        copy_context.post_copy(to_obj, new_object_size, semantics);
         trace!("Copied object from {} to {}", from, to_obj);
 
         #[cfg(feature = "clear_old_copy")]
@@ -119,15 +259,33 @@ impl ObjectModel<Ruby> for VMObjectModel {
     }
 
     fn get_size_when_copied(object: ObjectReference) -> usize {
-        Self::get_current_size(object)
+        Self::object_size_with_payload(Self::payload_size_when_copied(object))
     }
 
-    fn get_align_when_copied(_object: ObjectReference) -> usize {
-        todo!()
+    fn get_align_when_copied(object: ObjectReference) -> usize {
+        let Some(get_payload_alignment) = crate::upcalls().get_payload_alignment else {
+            return MIN_OBJ_ALIGN;
+        };
+        let align = get_payload_alignment(object).max(MIN_OBJ_ALIGN);
+        debug_assert!(
+            align.is_power_of_two(),
+            "Alignment {} of object {} is not a power of two",
+            align,
+            object
+        );
+        assert!(
+            align <= MAX_OBJ_ALIGN,
+            "Alignment {} of object {} exceeds MAX_OBJ_ALIGN ({})",
+            align,
+            object,
+            MAX_OBJ_ALIGN
+        );
+        align
     }
 
     fn get_align_offset_when_copied(_object: ObjectReference) -> usize {
-        todo!()
+        // The alignment applies to the payload, which starts after the prefix.
+        RubyObjectAccess::prefix_size()
     }
 
     fn dump_object(_object: ObjectReference) {
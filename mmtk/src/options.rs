@@ -0,0 +1,176 @@
+use std::sync::Mutex;
+
+/// Tuning options of the binding itself, as opposed to mmtk-core options and the
+/// `RubyBindingOptions` passed from the VM.
+///
+/// Each option can be set from an environment variable, or with
+/// `mmtk_builder_set_binding_option`.  The latter takes precedence.
+#[derive(Clone, Debug)]
+pub struct TuningOptions {
+    /// The number of PPPs in each `PinPPPChildren` packet.
+    pub ppp_packet_size: usize,
+    /// The number of `st_table` entries in each `UpdateTableEntriesParallel` packet.
+    pub st_entries_chunk_size: usize,
+    /// The number of `st_table` bins in each `UpdateTableBinsParallel` packet.
+    pub st_bins_chunk_size: usize,
+    /// The number of root objects collected before creating a root-processing packet.
+    pub object_buffer_size: usize,
+    /// If true, the chunk sizes above are upper bounds.  Actual chunk sizes are reduced so that
+    /// small tables and registries are still split among all GC workers.
+    pub adaptive_chunk_sizes: bool,
+}
+
+/// Each option has a name, an environment variable, and a `usize` value.  Booleans are 0 or 1.
+struct OptionDesc {
+    name: &'static str,
+    env_var: &'static str,
+    max: usize,
+    get: fn(&TuningOptions) -> usize,
+    set: fn(&mut TuningOptions, usize),
+}
+
+const MAX_CHUNK_SIZE: usize = 1 << 24;
+
+const OPTION_DESCS: &[OptionDesc] = &[
+    OptionDesc {
+        name: "ppp_packet_size",
+        env_var: "RUBY_MMTK_PPP_PACKET_SIZE",
+        max: MAX_CHUNK_SIZE,
+        get: |o| o.ppp_packet_size,
+        set: |o, v| o.ppp_packet_size = v,
+    },
+    OptionDesc {
+        name: "st_entries_chunk_size",
+        env_var: "RUBY_MMTK_ENTRIES_CHUNK_SIZE",
+        max: MAX_CHUNK_SIZE,
+        get: |o| o.st_entries_chunk_size,
+        set: |o, v| o.st_entries_chunk_size = v,
+    },
+    OptionDesc {
+        name: "st_bins_chunk_size",
+        env_var: "RUBY_MMTK_BINS_CHUNK_SIZE",
+        max: MAX_CHUNK_SIZE,
+        get: |o| o.st_bins_chunk_size,
+        set: |o, v| o.st_bins_chunk_size = v,
+    },
+    OptionDesc {
+        name: "object_buffer_size",
+        env_var: "RUBY_MMTK_OBJECT_BUFFER_SIZE",
+        max: MAX_CHUNK_SIZE,
+        get: |o| o.object_buffer_size,
+        set: |o, v| o.object_buffer_size = v,
+    },
+    OptionDesc {
+        name: "adaptive_chunk_sizes",
+        env_var: "RUBY_MMTK_ADAPTIVE_CHUNK_SIZES",
+        max: 1,
+        get: |o| o.adaptive_chunk_sizes as usize,
+        set: |o, v| o.adaptive_chunk_sizes = v != 0,
+    },
+];
+
+/// Options set with `mmtk_builder_set_binding_option` before the binding is initialized.
+/// `MMTKBuilder` belongs to mmtk-core and can't hold binding options, so we keep them here until
+/// `mmtk_init_binding` applies them.
+static BUILDER_OVERRIDES: Mutex<Vec<(&'static str, usize)>> = Mutex::new(Vec::new());
+
+impl Default for TuningOptions {
+    fn default() -> Self {
+        Self {
+            // I tried several packet sizes and 512 works pretty well.
+            ppp_packet_size: 512,
+            st_entries_chunk_size: 1024,
+            st_bins_chunk_size: 4096,
+            object_buffer_size: 4096,
+            adaptive_chunk_sizes: false,
+        }
+    }
+}
+
+impl TuningOptions {
+    /// The minimum chunk size when `adaptive_chunk_sizes` is enabled.  Smaller packets cost more
+    /// in scheduling than they gain in parallelism.
+    const MIN_ADAPTIVE_CHUNK_SIZE: usize = 64;
+    /// When `adaptive_chunk_sizes` is enabled, try to give each GC worker this many packets.
+    const PACKETS_PER_WORKER: usize = 4;
+
+    /// Create options from the defaults, environment variables and builder overrides, in that
+    /// order of precedence from low to high.
+    pub fn from_env_and_builder() -> Self {
+        let mut options = Self::default();
+
+        for desc in OPTION_DESCS {
+            let Ok(value) = std::env::var(desc.env_var) else {
+                continue;
+            };
+            match parse_value(desc, &value) {
+                Ok(value) => (desc.set)(&mut options, value),
+                Err(e) => warn!("Ignoring {}={}: {}", desc.env_var, value, e),
+            }
+        }
+
+        let overrides = BUILDER_OVERRIDES.lock().unwrap();
+        for (name, value) in overrides.iter() {
+            let desc = find_desc(name).unwrap();
+            (desc.set)(&mut options, *value);
+        }
+
+        options
+    }
+
+    /// Get the value of an option by name.  Booleans are returned as 0 or 1.
+    pub fn get(&self, name: &str) -> Option<usize> {
+        find_desc(name).map(|desc| (desc.get)(self))
+    }
+
+    /// Log the effective values of all options.
+    pub fn report(&self) {
+        for desc in OPTION_DESCS {
+            info!("Binding option {}: {}", desc.name, (desc.get)(self));
+        }
+    }
+
+    /// Return the chunk size for splitting `total` items into work packets, given the configured
+    /// chunk size.
+    pub fn chunk_size_for(&self, configured: usize, total: usize) -> usize {
+        if !self.adaptive_chunk_sizes {
+            return configured;
+        }
+        let threads = *crate::mmtk().get_options().threads;
+        let adapted = total
+            .div_ceil(threads * Self::PACKETS_PER_WORKER)
+            .max(Self::MIN_ADAPTIVE_CHUNK_SIZE);
+        adapted.min(configured)
+    }
+}
+
+fn find_desc(name: &str) -> Option<&'static OptionDesc> {
+    OPTION_DESCS.iter().find(|desc| desc.name == name)
+}
+
+fn parse_value(desc: &OptionDesc, value: &str) -> Result<usize, String> {
+    let value = match value {
+        "true" if desc.max == 1 => 1,
+        "false" if desc.max == 1 => 0,
+        _ => value
+            .parse::<usize>()
+            .map_err(|e| format!("not a number: {e}"))?,
+    };
+    if desc.max == 1 {
+        if value > 1 {
+            return Err("expected a boolean".to_string());
+        }
+    } else if value == 0 || value > desc.max {
+        return Err(format!("expected a number between 1 and {}", desc.max));
+    }
+    Ok(value)
+}
+
+/// Record an option to be applied when the binding is initialized.
+pub fn set_builder_override(name: &str, value: &str) -> Result<(), String> {
+    let desc = find_desc(name).ok_or_else(|| format!("unknown binding option: {name}"))?;
+    let value = parse_value(desc, value)?;
+    let mut overrides = BUILDER_OVERRIDES.lock().unwrap();
+    overrides.push((desc.name, value));
+    Ok(())
+}
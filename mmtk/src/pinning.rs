@@ -0,0 +1,192 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use mmtk::{
+    memory_manager,
+    scheduler::{GCWork, GCWorker},
+    util::ObjectReference,
+    MMTK,
+};
+
+use crate::Ruby;
+
+thread_local! {
+    /// The stack of pin scopes of the current thread.  Each scope holds the objects pinned in it.
+    static PIN_SCOPES: RefCell<Vec<Vec<ObjectReference>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Reference-counted pinning on top of the pin bit of mmtk-core.
+///
+/// The pin bit is set as long as the pin count of an object is positive.  Most objects are pinned
+/// at most once, so we only record the counts of objects pinned more than once, in a side table.
+/// Pins must be balanced by unpins, and a pinned object must be kept alive by whoever pinned it.
+///
+/// Objects in spaces that never move (LOS, immortal, ...) are always reported as pinned by
+/// mmtk-core, so their pin bits can't record the first pin.  Their pin counts are recorded in
+/// another side table instead.
+pub struct PinRegistry {
+    counts: Mutex<PinCounts>,
+}
+
+#[derive(Default)]
+struct PinCounts {
+    /// The number of extra pins, i.e. the pin count minus one, of movable objects pinned more
+    /// than once.
+    extra_pins: HashMap<ObjectReference, usize>,
+    /// The pin counts of pinned objects that never move.
+    unmovable_pins: HashMap<ObjectReference, usize>,
+}
+
+impl PinRegistry {
+    pub fn new() -> Self {
+        Self {
+            counts: Default::default(),
+        }
+    }
+
+    /// Pin `object`, and record the pin in the innermost pin scope of the current thread, if any.
+    /// Return true if the object was not pinned before.
+    pub fn pin(&self, object: ObjectReference) -> bool {
+        let newly_pinned = self.pin_many(&[object]) == 1;
+        PIN_SCOPES.with_borrow_mut(|scopes| {
+            if let Some(scope) = scopes.last_mut() {
+                scope.push(object);
+            }
+        });
+        newly_pinned
+    }
+
+    /// Remove one pin of `object`.  If it was pinned in any pin scope of the current thread, the
+    /// record in the innermost such scope is removed so that exiting that scope doesn't unpin it
+    /// again.  Return true if the object is no longer pinned.
+    pub fn unpin(&self, object: ObjectReference) -> bool {
+        PIN_SCOPES.with_borrow_mut(|scopes| {
+            for scope in scopes.iter_mut().rev() {
+                if let Some(index) = scope.iter().rposition(|o| *o == object) {
+                    scope.swap_remove(index);
+                    break;
+                }
+            }
+        });
+        self.unpin_many(&[object]) == 1
+    }
+
+    /// Add one pin to each object.  Return the number of objects that were not pinned before.
+    /// Not recorded in pin scopes.
+    pub fn pin_many(&self, objects: &[ObjectReference]) -> usize {
+        // Hold the lock while setting pin bits so that we don't race with `unpin_many`.
+        let mut counts = self.counts.lock().unwrap();
+        let mut newly_pinned = 0;
+        for object in objects.iter().copied() {
+            if !object.is_movable() {
+                let count = counts.unmovable_pins.entry(object).or_default();
+                *count += 1;
+                if *count == 1 {
+                    newly_pinned += 1;
+                }
+            } else if memory_manager::pin_object(object) {
+                newly_pinned += 1;
+            } else {
+                *counts.extra_pins.entry(object).or_default() += 1;
+            }
+        }
+        newly_pinned
+    }
+
+    /// Remove one pin from each object.  Return the number of objects that are no longer pinned.
+    pub fn unpin_many(&self, objects: &[ObjectReference]) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let mut unpinned = 0;
+        for object in objects.iter().copied() {
+            let movable = object.is_movable();
+            let map = if movable {
+                &mut counts.extra_pins
+            } else {
+                &mut counts.unmovable_pins
+            };
+            if let Some(count) = map.get_mut(&object) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(&object);
+                    if !movable {
+                        unpinned += 1;
+                    }
+                }
+            } else if movable && memory_manager::unpin_object(object) {
+                unpinned += 1;
+            } else {
+                warn!("Unpinning an object that is not pinned: {object}");
+            }
+        }
+        unpinned
+    }
+
+    /// Return the pin count of `object`.
+    pub fn pin_count(&self, object: ObjectReference) -> usize {
+        let counts = self.counts.lock().unwrap();
+        if !object.is_movable() {
+            counts.unmovable_pins.get(&object).copied().unwrap_or(0)
+        } else if memory_manager::is_pinned(object) {
+            1 + counts.extra_pins.get(&object).copied().unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    /// Enter a pin scope on the current thread.
+    pub fn enter_scope(&self) {
+        PIN_SCOPES.with_borrow_mut(|scopes| scopes.push(vec![]));
+    }
+
+    /// Exit the innermost pin scope of the current thread, and unpin all objects pinned in it.
+    pub fn exit_scope(&self) {
+        let objects = PIN_SCOPES.with_borrow_mut(|scopes| {
+            scopes
+                .pop()
+                .expect("mmtk_pin_scope_exit called without mmtk_pin_scope_enter")
+        });
+        debug!("Exiting pin scope.  Unpinning {} objects", objects.len());
+        self.unpin_many(&objects);
+    }
+
+    /// Create work packets that remove dead objects from the side tables.  Pinned objects should
+    /// be kept alive by whoever pinned them, but a buggy extension may forget to unpin an object
+    /// before dropping it.
+    pub fn cleanup_packets(&self) -> Vec<Box<dyn GCWork<Ruby>>> {
+        vec![Box::new(RemoveDeadPins)]
+    }
+}
+
+impl Default for PinRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct RemoveDeadPins;
+
+impl GCWork<Ruby> for RemoveDeadPins {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+        let mut counts = crate::binding().pin_registry.counts.lock().unwrap();
+        let PinCounts {
+            extra_pins,
+            unmovable_pins,
+        } = &mut *counts;
+        let old_size = extra_pins.len() + unmovable_pins.len();
+        for map in [extra_pins, unmovable_pins] {
+            map.retain(|object, _| {
+                // Pinned objects never move, so live keys are still valid.
+                let live = object.is_reachable();
+                if !live {
+                    warn!("Removing pins of dead object: {object}");
+                }
+                live
+            });
+        }
+        debug!(
+            "Removed pins of {} dead objects",
+            old_size - counts.extra_pins.len() - counts.unmovable_pins.len()
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 use mmtk::{
@@ -7,11 +9,23 @@ use mmtk::{
     MMTK,
 };
 
-use crate::{abi::GCThreadTLS, upcalls, Ruby};
+use crate::{
+    abi::{GCThreadTLS, PPPStats, PPP_REASON_UNKNOWN},
+    upcalls,
+    utils::ChunkedVecCollector,
+    Ruby,
+};
+
+/// Why an object is a PPP.  The codes are defined by the VM, and are only used for statistics.
+pub type PPPReason = u32;
 
 pub struct PPPRegistry {
-    ppps: Mutex<Vec<ObjectReference>>,
+    /// Maps each PPP to the reason it was registered.  Registering an object again updates its
+    /// reason, and does not add a duplicated entry.
+    ppps: Mutex<HashMap<ObjectReference, PPPReason>>,
     pinned_ppp_children: Mutex<Vec<ObjectReference>>,
+    /// The number of children pinned for PPPs of each reason in the last moving GC.
+    pinned_children_by_reason: Mutex<HashMap<PPPReason, usize>>,
 }
 
 impl PPPRegistry {
@@ -19,18 +33,35 @@ impl PPPRegistry {
         Self {
             ppps: Default::default(),
             pinned_ppp_children: Default::default(),
+            pinned_children_by_reason: Default::default(),
         }
     }
 
-    pub fn register(&self, object: ObjectReference) {
+    pub fn register(&self, object: ObjectReference, reason: PPPReason) {
         let mut ppps = self.ppps.lock().unwrap();
-        ppps.push(object);
+        ppps.insert(object, reason);
     }
 
-    pub fn register_many(&self, objects: &[ObjectReference]) {
+    pub fn register_many(&self, objects: &[ObjectReference], reason: PPPReason) {
         let mut ppps = self.ppps.lock().unwrap();
         for object in objects.iter().copied() {
-            ppps.push(object);
+            ppps.insert(object, reason);
+        }
+    }
+
+    /// Get the statistics of PPPs registered with `reason`.
+    pub fn get_stats(&self, reason: PPPReason) -> PPPStats {
+        let num_ppps = {
+            let ppps = self.ppps.lock().unwrap();
+            ppps.values().filter(|r| **r == reason).count()
+        };
+        let num_pinned_children = {
+            let pinned_children_by_reason = self.pinned_children_by_reason.lock().unwrap();
+            pinned_children_by_reason.get(&reason).copied().unwrap_or(0)
+        };
+        PPPStats {
+            num_ppps,
+            num_pinned_children,
         }
     }
 
@@ -45,28 +76,34 @@ impl PPPRegistry {
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
         let worker = gc_tls.worker();
 
+        self.pinned_children_by_reason
+            .try_lock()
+            .expect("PPPRegistry should not have races during GC.")
+            .clear();
+
         {
             let ppps = self
                 .ppps
                 .try_lock()
                 .expect("PPPRegistry should not have races during GC.");
 
-            // I tried several packet sizes and 512 works pretty well.  It should be adjustable.
-            let packet_size = 512;
-            let work_packets = ppps
-                .chunks(packet_size)
-                .map(|chunk| {
-                    Box::new(PinPPPChildren {
-                        ppps: chunk.to_vec(),
-                    }) as _
-                })
+            let tuning = &crate::binding().tuning;
+            let packet_size = tuning.chunk_size_for(tuning.ppp_packet_size, ppps.len());
+            let mut collector = ChunkedVecCollector::new(packet_size);
+            collector.extend(ppps.iter().map(|(object, reason)| (*object, *reason)));
+            let work_packets = collector
+                .into_vecs()
+                .into_iter()
+                .map(|ppps| Box::new(PinPPPChildren { ppps }) as _)
                 .collect();
 
             worker.scheduler().work_buckets[WorkBucketStage::Prepare].bulk_add(work_packets);
         }
     }
 
-    pub fn cleanup_ppps(&self, worker: &mut GCWorker<Ruby>) {
+    /// Remove dead PPPs and unpin the children pinned in this GC.  `then` is scheduled after the
+    /// children are unpinned.
+    pub fn cleanup_ppps(&self, worker: &mut GCWorker<Ruby>, then: Vec<Box<dyn GCWork<Ruby>>>) {
         worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].add(RemoveDeadPPPs);
         if crate::mmtk().get_plan().current_gc_may_move_object() {
             let packet = {
@@ -76,11 +113,13 @@ impl PPPRegistry {
                     .expect("Unexpected contention on pinned_ppp_children");
                 UnpinPPPChildren {
                     children: std::mem::take(&mut pinned_ppp_children),
+                    then,
                 }
             };
 
             worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].add(packet);
         } else {
+            worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(then);
             debug!("Skipping unpinning PPP children because the current GC is non-copying.");
             debug_assert_eq!(
                 {
@@ -103,16 +142,18 @@ impl Default for PPPRegistry {
 }
 
 struct PinPPPChildren {
-    ppps: Vec<ObjectReference>,
+    ppps: Vec<(ObjectReference, PPPReason)>,
 }
 
 impl GCWork<Ruby> for PinPPPChildren {
-    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, mmtk: &'static MMTK<Ruby>) {
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(worker.tls) };
         let num_ppps = self.ppps.len();
         let mut ppp_children = vec![];
         let mut newly_pinned_ppp_children = vec![];
         let mut num_no_longer_ppps = 0usize;
+        // The reason of the PPP being visited.
+        let current_reason = Cell::new(PPP_REASON_UNKNOWN);
 
         let visit_object = |_worker, target_object: ObjectReference, pin| {
             log::trace!(
@@ -121,7 +162,7 @@ impl GCWork<Ruby> for PinPPPChildren {
                 target_object
             );
             if pin {
-                ppp_children.push(target_object);
+                ppp_children.push((target_object, current_reason.get()));
             }
             target_object
         };
@@ -129,20 +170,37 @@ impl GCWork<Ruby> for PinPPPChildren {
         gc_tls
             .object_closure
             .set_temporarily_and_run_code(visit_object, || {
-                for obj in self.ppps.iter().cloned() {
-                    log::trace!("  PPP: {}", obj);
+                for (obj, reason) in self.ppps.iter().copied() {
+                    log::trace!("  PPP: {} (reason: {})", obj, reason);
                     if (upcalls().is_no_longer_ppp)(obj) {
                         num_no_longer_ppps += 1;
                         log::trace!("    No longer PPP. Skip: {}", obj);
                         continue;
                     }
+                    current_reason.set(reason);
                     (upcalls().call_gc_mark_children)(obj);
                 }
             });
 
-        for target_object in ppp_children {
+        // Only pin children that may actually be moved in this GC.  Objects in non-moving spaces
+        // (LOS, immortal, ...) never move.  In nursery GCs, old objects never move, and they are
+        // already considered reachable before the transitive closure.
+        let is_nursery_gc =
+            (mmtk.get_plan().generational()).is_some_and(|gen| gen.is_current_gc_nursery());
+        let mut num_skipped_children = 0usize;
+
+        let mut pinned_by_reason = HashMap::<PPPReason, usize>::new();
+        for (target_object, reason) in ppp_children {
+            if !target_object.is_movable() || (is_nursery_gc && target_object.is_reachable()) {
+                log::trace!("    Skip pinning non-moving child: {}", target_object);
+                num_skipped_children += 1;
+                continue;
+            }
+            // Only record children whose pin bits are set by us, so that unpinning them later never
+            // removes pins of others.
             if memory_manager::pin_object(target_object) {
                 newly_pinned_ppp_children.push(target_object);
+                *pinned_by_reason.entry(reason).or_default() += 1;
             }
         }
 
@@ -153,17 +211,21 @@ impl GCWork<Ruby> for PinPPPChildren {
             pin_ppp_children,
             num_ppps,
             num_no_longer_ppps,
-            num_pinned_children
+            num_pinned_children,
+            num_skipped_children
         );
 
+        let registry = &crate::binding().ppp_registry;
         {
-            let mut pinned_ppp_children = crate::binding()
-                .ppp_registry
-                .pinned_ppp_children
-                .lock()
-                .unwrap();
+            let mut pinned_ppp_children = registry.pinned_ppp_children.lock().unwrap();
             pinned_ppp_children.append(&mut newly_pinned_ppp_children);
         }
+        {
+            let mut pinned_children_by_reason = registry.pinned_children_by_reason.lock().unwrap();
+            for (reason, count) in pinned_by_reason {
+                *pinned_children_by_reason.entry(reason).or_default() += count;
+            }
+        }
     }
 }
 
@@ -184,23 +246,28 @@ impl GCWork<Ruby> for RemoveDeadPPPs {
             let mut num_no_longer_ppps = 0usize;
             let mut num_dead_ppps = 0usize;
 
-            ppps.retain_mut(|obj| {
-                if obj.is_live() {
-                    let new_obj = obj.get_forwarded_object().unwrap_or(*obj);
-                    if (upcalls().is_no_longer_ppp)(new_obj) {
-                        num_no_longer_ppps += 1;
-                        log::trace!("  No longer PPP. Remove: {}", new_obj);
-                        false
-                    } else {
-                        *obj = new_obj;
-                        true
-                    }
-                } else {
+            // Keys that are forwarded are removed, and inserted again with their new addresses
+            // after all dead keys are removed.
+            let mut moved_ppps = vec![];
+            ppps.retain(|obj, reason| {
+                if !obj.is_live() {
                     num_dead_ppps += 1;
-                    log::trace!("  Dead PPP removed: {}", *obj);
-                    false
+                    log::trace!("  Dead PPP removed: {}", obj);
+                    return false;
                 }
+                let new_obj = obj.get_forwarded_object();
+                if (upcalls().is_no_longer_ppp)(new_obj.unwrap_or(*obj)) {
+                    num_no_longer_ppps += 1;
+                    log::trace!("  No longer PPP. Remove: {}", new_obj.unwrap_or(*obj));
+                    return false;
+                }
+                if let Some(new_obj) = new_obj {
+                    moved_ppps.push((new_obj, *reason));
+                    return false;
+                }
+                true
             });
+            ppps.extend(moved_ppps);
 
             probe!(
                 mmtk_ruby,
@@ -215,10 +282,12 @@ impl GCWork<Ruby> for RemoveDeadPPPs {
 
 struct UnpinPPPChildren {
     children: Vec<ObjectReference>,
+    /// Packets scheduled after unpinning.
+    then: Vec<Box<dyn GCWork<Ruby>>>,
 }
 
 impl GCWork<Ruby> for UnpinPPPChildren {
-    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
         log::debug!("Unpinning pinned PPP children...");
 
         let num_children = self.children.len();
@@ -229,5 +298,8 @@ impl GCWork<Ruby> for UnpinPPPChildren {
             let unpinned = memory_manager::unpin_object(*obj);
             debug_assert!(unpinned);
         }
+
+        worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure]
+            .bulk_add(std::mem::take(&mut self.then));
     }
 }
@@ -65,8 +65,8 @@ impl Scanning<Ruby> for VMScanning {
             });
     }
 
-    fn notify_initial_thread_scan_complete(_partial_scan: bool, _tls: VMWorkerThread) {
-        // Do nothing
+    fn notify_initial_thread_scan_complete(partial_scan: bool, _tls: VMWorkerThread) {
+        debug!("Initial thread scan complete.  partial_scan: {partial_scan}");
     }
 
     fn scan_roots_in_mutator_thread(
@@ -75,9 +75,16 @@ impl Scanning<Ruby> for VMScanning {
         mut factory: impl RootsWorkFactory<RubySlot>,
     ) {
         let gc_tls = unsafe { GCThreadTLS::from_vwt_check(tls) };
-        Self::collect_object_roots_in("scan_thread_root", gc_tls, &mut factory, || {
-            (upcalls().scan_roots_in_mutator_thread)(mutator.get_tls(), tls);
-        });
+        if crate::binding().can_scan_stacks_partially() {
+            Self::collect_object_roots_in("scan_thread_root_partial", gc_tls, &mut factory, || {
+                // `RubyBinding::new` checks that it is present if return barriers are enabled.
+                (upcalls().scan_roots_in_mutator_thread_partially.unwrap())(mutator.get_tls(), tls);
+            });
+        } else {
+            Self::collect_object_roots_in("scan_thread_root", gc_tls, &mut factory, || {
+                (upcalls().scan_roots_in_mutator_thread)(mutator.get_tls(), tls);
+            });
+        }
     }
 
     fn scan_vm_specific_roots(tls: VMWorkerThread, factory: impl RootsWorkFactory<RubySlot>) {
@@ -110,12 +117,31 @@ impl Scanning<Ruby> for VMScanning {
                     .wb_unprotected_objects
                     .try_lock()
                     .expect("Someone is holding the lock of wb_unprotected_objects?");
-                if guard.is_empty() {
+                // The VM may unprotect objects that are already old.  They must be scanned, too.
+                // Objects registered while young are skipped by `ScanWbUnprotectedRoots` because
+                // they are not reachable yet.
+                let young_guard = crate::binding()
+                    .young_wb_unprotected_objects
+                    .try_lock()
+                    .expect("Someone is holding the lock of young_wb_unprotected_objects?");
+                if guard.is_empty() && young_guard.is_empty() {
                     break 'gen_wb_unprotected_work;
                 }
 
+                let binding = crate::binding();
                 let mut collector = ChunkedVecCollector::new(128);
-                collector.extend(guard.iter().copied());
+                collector.extend(
+                    guard
+                        .iter()
+                        .chain(young_guard.iter())
+                        .copied()
+                        .filter(|object| binding.take_wb_unprotected_object_dirty(*object)),
+                );
+                debug!(
+                    "Scanning dirty WB-unprotected objects: {} of {} objects",
+                    collector.len(),
+                    guard.len() + young_guard.len()
+                );
                 collector.into_vecs()
             };
 
@@ -132,21 +158,60 @@ impl Scanning<Ruby> for VMScanning {
     }
 
     fn supports_return_barrier() -> bool {
-        false
+        crate::binding().options.return_barrier
     }
 
     fn prepare_for_roots_re_scanning() {
-        todo!()
+        // Root re-scanning must give the same root set as the first scan.  Frames scanned in the
+        // first round are now below the return barrier, so we must scan all frames.
+        crate::binding().force_full_stack_scan();
     }
 
     fn process_weak_refs(
         worker: &mut GCWorker<Ruby>,
         tracer_context: impl ObjectTracerContext<Ruby>,
     ) -> bool {
+        if crate::binding().weak_proc.weak_refs_processed() {
+            return crate::binding()
+                .weak_proc
+                .resurrect_dead_obj_free_candidates(worker, tracer_context);
+        }
+
+        // Keep tracing from ephemeron values until we reach a fixpoint.  mmtk-core will call this
+        // function again after finishing the transitive closure if we return true.
+        if crate::binding()
+            .weak_slots
+            .trace_ephemeron_values(worker, tracer_context.clone())
+        {
+            return true;
+        }
+
         crate::binding()
             .weak_proc
             .process_weak_stuff(worker, tracer_context);
-        crate::binding().ppp_registry.cleanup_ppps(worker);
+        // Free dead objects after weak slots are processed because slots may be in the memory
+        // freed by `obj_free`.
+        let obj_free_packets = crate::binding().weak_proc.obj_free_candidate_packets();
+        crate::binding()
+            .weak_slots
+            .process_weak_slots(worker, obj_free_packets);
+        crate::binding()
+            .dead_object_tracker
+            .process_tracked_objects(worker);
+        let pin_cleanup_packets = crate::binding().pin_registry.cleanup_packets();
+        crate::binding()
+            .ppp_registry
+            .cleanup_ppps(worker, pin_cleanup_packets);
+        crate::binding()
+            .move_listeners
+            .process_watched_objects(worker);
+
+        // mmtk-core calls this function again after all the work above is done.  Only then are
+        // dead `obj_free` candidates resurrected, so that weak references to them are cleared.
+        if crate::binding().options.lazy_obj_free {
+            crate::binding().weak_proc.defer_obj_free_resurrection();
+            return true;
+        }
         false
     }
 
@@ -159,14 +224,13 @@ impl Scanning<Ruby> for VMScanning {
 }
 
 impl VMScanning {
-    const OBJECT_BUFFER_SIZE: usize = 4096;
-
     fn collect_object_roots_in<F: FnOnce()>(
         root_scan_kind: &str,
         gc_tls: &mut GCThreadTLS,
         factory: &mut impl RootsWorkFactory<RubySlot>,
         callback: F,
     ) {
+        let object_buffer_size = crate::binding().tuning.object_buffer_size;
         let mut buffer: Vec<ObjectReference> = Vec::new();
         let visit_object = |_, object: ObjectReference, pin| {
             debug!(
@@ -184,7 +248,7 @@ impl VMScanning {
                 "Root does not point to MMTk object.  object: {object}"
             );
             buffer.push(object);
-            if buffer.len() >= Self::OBJECT_BUFFER_SIZE {
+            if buffer.len() >= object_buffer_size {
                 factory.create_process_pinning_roots_work(std::mem::take(&mut buffer));
             }
             object
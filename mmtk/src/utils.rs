@@ -1,8 +1,14 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::LocalKey;
 
 use atomic_refcell::AtomicRefCell;
+use mmtk::memory_manager;
 use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::util::{Address, ObjectReference};
 
+use crate::abi::MIN_OBJ_ALIGN;
 use crate::Ruby;
 
 pub struct ChunkedVecCollector<T> {
@@ -27,6 +33,15 @@ impl<T> ChunkedVecCollector<T> {
         }
     }
 
+    /// The total number of items added so far.
+    pub fn len(&self) -> usize {
+        self.vecs.iter().map(|vec| vec.len()).sum::<usize>() + self.current_vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     fn flush(&mut self) {
         let new_vec = Vec::with_capacity(self.chunk_size);
         let old_vec = std::mem::replace(&mut self.current_vec, new_vec);
@@ -49,6 +64,79 @@ impl<A> Extend<A> for ChunkedVecCollector<A> {
     }
 }
 
+/// A buffer of items added by one mutator thread.  See `PerThreadBuffers`.
+pub struct ThreadBuffer<T> {
+    items: UnsafeCell<Vec<T>>,
+}
+
+// Only the owning thread accesses it while mutators are running, and only GC workers access it
+// while mutators are stopped.  So it needs no lock.
+unsafe impl<T: Send> Sync for ThreadBuffer<T> {}
+
+impl<T> ThreadBuffer<T> {
+    /// Run `f` with the buffer of the current thread held in `key`.  If the buffer has been
+    /// destroyed because the thread is exiting, return `f` back without calling it.
+    pub fn with_current<R, F: FnOnce(&mut Vec<T>) -> R>(
+        key: &'static LocalKey<Arc<Self>>,
+        f: F,
+    ) -> Result<R, F> {
+        let mut f = Some(f);
+        key.try_with(|buffer| {
+            // Safety: Only the current thread accesses its own buffer while it is running.
+            (f.take().unwrap())(unsafe { &mut *buffer.items.get() })
+        })
+        .map_err(|_| f.take().unwrap())
+    }
+}
+
+/// Per-thread buffers that let mutators add items without locking.  GC workers take the items
+/// of all threads while mutators are stopped.
+///
+/// Each buffer is held in a `thread_local!` initialized with `register`.
+pub struct PerThreadBuffers<T> {
+    buffers: Mutex<Vec<Arc<ThreadBuffer<T>>>>,
+}
+
+impl<T> Default for PerThreadBuffers<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PerThreadBuffers<T> {
+    pub fn new() -> Self {
+        Self {
+            buffers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Create a buffer for the current thread, and register it.
+    pub fn register(&self) -> Arc<ThreadBuffer<T>> {
+        let buffer = Arc::new(ThreadBuffer {
+            items: UnsafeCell::new(vec![]),
+        });
+        self.buffers.lock().unwrap().push(buffer.clone());
+        buffer
+    }
+
+    /// Take the items of all buffers, one vector per non-empty buffer.  Mutators must not be
+    /// adding items concurrently.  Buffers of exited threads are removed.
+    pub fn take_all(&self) -> Vec<Vec<T>> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let mut result = vec![];
+        for buffer in buffers.iter() {
+            // Safety: The owning thread is not adding items now.
+            let items = std::mem::take(unsafe { &mut *buffer.items.get() });
+            if !items.is_empty() {
+                result.push(items);
+            }
+        }
+        // Buffers only referenced from here belong to exited threads.
+        buffers.retain(|buffer| Arc::strong_count(buffer) > 1);
+        result
+    }
+}
+
 pub struct AfterAll {
     counter: AtomicUsize,
     stage: WorkBucketStage,
@@ -89,3 +177,176 @@ impl AfterAll {
         false
     }
 }
+
+/// A bitmap with one bit for every `MIN_OBJ_ALIGN` bytes of the MMTk heap, i.e. one bit for each
+/// possible object reference.
+///
+/// Like the side metadata of mmtk-core, the bitmap is allocated per chunk, and only for chunks
+/// where a bit has ever been set, so it never reserves memory for the whole heap address range.
+/// The chunk bitmaps are found through a two-level table, and both levels are allocated on demand
+/// with compare-and-swap.  All operations are lock-free.
+pub struct HeapBitmap {
+    heap_start: Address,
+    heap_end: Address,
+    /// Each group covers `CHUNKS_PER_GROUP` consecutive chunks.
+    groups: Box<[AtomicPtr<ChunkGroup>]>,
+}
+
+struct ChunkGroup {
+    /// The bitmap of each chunk, with `HeapBitmap::BITMAP_BYTES_PER_CHUNK` bytes, or null.
+    chunks: [AtomicPtr<AtomicU8>; HeapBitmap::CHUNKS_PER_GROUP],
+}
+
+impl HeapBitmap {
+    const BYTES_PER_BIT: usize = MIN_OBJ_ALIGN;
+    const BYTES_PER_BITMAP_BYTE: usize = Self::BYTES_PER_BIT * 8;
+    /// The same as the chunk size of mmtk-core.
+    const LOG_BYTES_IN_CHUNK: usize = 22;
+    const BYTES_IN_CHUNK: usize = 1 << Self::LOG_BYTES_IN_CHUNK;
+    const BITMAP_BYTES_PER_CHUNK: usize = Self::BYTES_IN_CHUNK / Self::BYTES_PER_BITMAP_BYTE;
+    const CHUNKS_PER_GROUP: usize = 1024;
+
+    /// Create a bitmap covering the heap.  MMTk must have been initialized.  No chunk bitmap is
+    /// allocated until a bit is set.
+    pub fn new() -> Self {
+        let heap_start = memory_manager::starting_heap_address();
+        let heap_end = memory_manager::last_heap_address();
+        let num_chunks = (heap_end - heap_start).div_ceil(Self::BYTES_IN_CHUNK);
+        let num_groups = num_chunks.div_ceil(Self::CHUNKS_PER_GROUP);
+        let groups = (0..num_groups)
+            .map(|_| AtomicPtr::new(std::ptr::null_mut()))
+            .collect();
+        Self {
+            heap_start,
+            heap_end,
+            groups,
+        }
+    }
+
+    /// Return the chunk bitmap and the bit of `object`.  The chunk bitmap is allocated if
+    /// `allocate` is true, or `None` is returned if it has not been allocated.
+    fn locate(&self, object: ObjectReference, allocate: bool) -> Option<(&AtomicU8, u8)> {
+        let addr = object.to_raw_address();
+        debug_assert!(
+            self.heap_start <= addr && addr < self.heap_end,
+            "{object} is not in the heap"
+        );
+        let offset = addr - self.heap_start;
+        let chunk_index = offset >> Self::LOG_BYTES_IN_CHUNK;
+        let offset_in_chunk = offset & (Self::BYTES_IN_CHUNK - 1);
+
+        let group_slot = &self.groups[chunk_index / Self::CHUNKS_PER_GROUP];
+        let group = load_or_allocate(
+            group_slot,
+            allocate,
+            Self::alloc_chunk_group,
+            Self::free_chunk_group,
+        )?;
+        let chunk_slot = unsafe { &(*group).chunks[chunk_index % Self::CHUNKS_PER_GROUP] };
+        let chunk = load_or_allocate(
+            chunk_slot,
+            allocate,
+            Self::alloc_chunk_bitmap,
+            Self::free_chunk_bitmap,
+        )?;
+
+        let byte_index = offset_in_chunk / Self::BYTES_PER_BITMAP_BYTE;
+        let bit_index = (offset_in_chunk / Self::BYTES_PER_BIT) % 8;
+        let byte = unsafe { &*chunk.add(byte_index) };
+        Some((byte, 1u8 << bit_index))
+    }
+
+    fn alloc_chunk_group() -> *mut ChunkGroup {
+        Box::into_raw(Box::new(ChunkGroup {
+            chunks: std::array::from_fn(|_| AtomicPtr::new(std::ptr::null_mut())),
+        }))
+    }
+
+    unsafe fn free_chunk_group(group: *mut ChunkGroup) {
+        drop(Box::from_raw(group));
+    }
+
+    fn alloc_chunk_bitmap() -> *mut AtomicU8 {
+        let bytes = (0..Self::BITMAP_BYTES_PER_CHUNK)
+            .map(|_| AtomicU8::new(0))
+            .collect::<Box<[_]>>();
+        Box::into_raw(bytes) as *mut AtomicU8
+    }
+
+    unsafe fn free_chunk_bitmap(chunk: *mut AtomicU8) {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            chunk,
+            Self::BITMAP_BYTES_PER_CHUNK,
+        )));
+    }
+
+    /// Set the bit of `object`.  Return true if it was not set before.
+    pub fn set(&self, object: ObjectReference) -> bool {
+        let (byte, mask) = self.locate(object, true).unwrap();
+        byte.fetch_or(mask, Ordering::SeqCst) & mask == 0
+    }
+
+    /// Clear the bit of `object`.  Return true if it was set before.
+    pub fn clear(&self, object: ObjectReference) -> bool {
+        let Some((byte, mask)) = self.locate(object, false) else {
+            return false;
+        };
+        byte.fetch_and(!mask, Ordering::SeqCst) & mask != 0
+    }
+
+    pub fn is_set(&self, object: ObjectReference) -> bool {
+        let Some((byte, mask)) = self.locate(object, false) else {
+            return false;
+        };
+        byte.load(Ordering::Relaxed) & mask != 0
+    }
+}
+
+/// Load the pointer in `slot`.  If it is null, return `None` if `allocate` is false, or install a
+/// pointer returned by `alloc` otherwise.  If another thread installs a pointer first, ours is
+/// freed with `free`.
+fn load_or_allocate<T>(
+    slot: &AtomicPtr<T>,
+    allocate: bool,
+    alloc: impl FnOnce() -> *mut T,
+    free: unsafe fn(*mut T),
+) -> Option<*mut T> {
+    let existing = slot.load(Ordering::Acquire);
+    if !existing.is_null() {
+        return Some(existing);
+    }
+    if !allocate {
+        return None;
+    }
+    let new = alloc();
+    match slot.compare_exchange(
+        std::ptr::null_mut(),
+        new,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => Some(new),
+        Err(existing) => {
+            unsafe { free(new) };
+            Some(existing)
+        }
+    }
+}
+
+impl Drop for HeapBitmap {
+    fn drop(&mut self) {
+        for group_slot in self.groups.iter() {
+            let group = group_slot.load(Ordering::Acquire);
+            if group.is_null() {
+                continue;
+            }
+            for chunk_slot in unsafe { &(*group).chunks } {
+                let chunk = chunk_slot.load(Ordering::Acquire);
+                if !chunk.is_null() {
+                    unsafe { HeapBitmap::free_chunk_bitmap(chunk) };
+                }
+            }
+            unsafe { HeapBitmap::free_chunk_group(group) };
+        }
+    }
+}
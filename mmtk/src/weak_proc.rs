@@ -1,24 +1,175 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use mmtk::{
     scheduler::{GCWork, GCWorker, WorkBucketStage},
     util::ObjectReference,
-    vm::ObjectTracerContext,
+    vm::{ObjectTracer, ObjectTracerContext},
 };
 
 use crate::{
     abi::{st_table, GCThreadTLS},
     binding::MovedGIVTblEntry,
     extra_assert, is_mmtk_object_safe, upcalls,
-    utils::AfterAll,
+    utils::{AfterAll, ChunkedVecCollector, PerThreadBuffers, ThreadBuffer},
     Ruby,
 };
 
+/// The state of a weak table at the end of the last GC.
+struct WeakTableWatermark {
+    /// The number of times the table has been rebuilt.  Rebuilding a table reorders its entries.
+    rebuilds_num: usize,
+    /// `entries_bound` at the end of the last GC.  Entries at and above this index have been
+    /// inserted since then.
+    entries_bound: usize,
+}
+
+/// How the entries of a weak table are updated.
+#[derive(Clone, Copy)]
+pub enum WeakTableKind {
+    /// Both keys and records are `VALUE`s.  An entry is removed if its key is dead (if
+    /// `weak_keys`) or its record is dead (if `weak_values`).  Other references are forwarded.
+    Generic { weak_keys: bool, weak_values: bool },
+    /// The `generic_iv_tbl_`.  Keys are weak.  Records are `gen_ivtbl` pointers, and are freed
+    /// when their entries are removed.
+    GenericIvTbl,
+}
+
+/// Describes a weak table processed by `WeakProcessor::process_weak_table_chunked`.
+#[derive(Clone)]
+pub struct WeakTableSpec {
+    /// The name of the table for logging and tracing.
+    pub name: Cow<'static, str>,
+    pub kind: WeakTableKind,
+    /// The records of existing entries are never replaced.
+    pub insert_only: bool,
+    /// Keys are hashed by their addresses.  Forwarding a key invalidates its hash, so the table
+    /// has to be rehashed instead of having its bins updated in parallel.
+    pub address_hashed: bool,
+}
+
+const FROZEN_STRINGS_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("frozen strings"),
+    kind: WeakTableKind::Generic {
+        weak_keys: true,
+        weak_values: false,
+    },
+    insert_only: true,
+    address_hashed: false,
+};
+
+const GLOBAL_SYMBOLS_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("global symbols"),
+    kind: WeakTableKind::Generic {
+        weak_keys: false,
+        weak_values: true,
+    },
+    insert_only: true,
+    address_hashed: false,
+};
+
+const GENERIC_IV_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("generic_iv_tbl"),
+    kind: WeakTableKind::GenericIvTbl,
+    insert_only: false,
+    address_hashed: true,
+};
+
+const OVERLOADED_CME_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("overloaded cme"),
+    kind: WeakTableKind::Generic {
+        weak_keys: true,
+        weak_values: true,
+    },
+    insert_only: false,
+    address_hashed: true,
+};
+
+const CI_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("ci"),
+    kind: WeakTableKind::Generic {
+        weak_keys: true,
+        weak_values: false,
+    },
+    insert_only: false,
+    address_hashed: false,
+};
+
+/// Keys are objects with finalizers, and records are their finalizer procs.  Both are strong.
+/// Keys never die because mmtk-core resurrects dead objects with finalizers, and records are
+/// scanned as roots.  So we only forward references.
+const FINALIZER_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("finalizer"),
+    kind: WeakTableKind::Generic {
+        weak_keys: false,
+        weak_values: false,
+    },
+    insert_only: false,
+    address_hashed: true,
+};
+
+/// Keys are objects, and records are their IDs.
+const OBJ_TO_ID_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("obj_to_id"),
+    kind: WeakTableKind::Generic {
+        weak_keys: true,
+        weak_values: false,
+    },
+    insert_only: false,
+    address_hashed: true,
+};
+
+/// Keys are IDs (hashed by value, even for bignums), and records are objects.
+const ID_TO_OBJ_TABLE: WeakTableSpec = WeakTableSpec {
+    name: Cow::Borrowed("id_to_obj"),
+    kind: WeakTableKind::Generic {
+        weak_keys: false,
+        weak_values: true,
+    },
+    insert_only: false,
+    address_hashed: false,
+};
+
+/// A weak `st_table` registered by the VM or C extensions at run time.
+struct RegisteredWeakTable {
+    table: *mut st_table,
+    spec: WeakTableSpec,
+}
+
+unsafe impl Send for RegisteredWeakTable {}
+
+thread_local! {
+    /// The `obj_free` candidate buffer of the current thread.  It is registered to the
+    /// `WeakProcessor` when first used.
+    static OBJ_FREE_CANDIDATE_BUFFER: Arc<ThreadBuffer<ObjectReference>> =
+        crate::binding().weak_proc.obj_free_buffers.register();
+}
+
 pub struct WeakProcessor {
-    /// Objects that needs `obj_free` called when dying.
-    /// If it is a bottleneck, replace it with a lock-free data structure,
-    /// or add candidates in batch.
-    obj_free_candidates: Mutex<Vec<ObjectReference>>,
+    /// Objects that needs `obj_free` called when dying, which survived previous GCs.  Survivors
+    /// are collected into vectors of `OBJ_FREE_CANDIDATES_PER_PACKET` objects so that each vector
+    /// can become a work packet in the next GC without copying.
+    obj_free_candidates: Mutex<Vec<Vec<ObjectReference>>>,
+    /// Per-thread buffers of newly added `obj_free` candidates.
+    obj_free_buffers: PerThreadBuffers<ObjectReference>,
+    /// Dead objects waiting for mutators to call `obj_free` on them.  Only used if
+    /// `RubyBindingOptions::lazy_obj_free` is true.  They are kept alive until they are freed or
+    /// the next GC starts, so their memory is not reused.
+    pending_obj_free: Mutex<Vec<ObjectReference>>,
+    /// `obj_free` candidates found dead in the current GC, to be moved to `pending_obj_free`.
+    /// Only used if `RubyBindingOptions::lazy_obj_free` is true.
+    newly_dead_obj_free: Mutex<Vec<ObjectReference>>,
+    /// True if weak references have been processed in the current GC, and dead `obj_free`
+    /// candidates are yet to be resurrected.
+    weak_refs_processed: AtomicBool,
+    /// True if dead `obj_free` candidates have been resurrected in the current GC.
+    obj_free_resurrected: AtomicBool,
+    /// Watermarks of weak tables, indexed by the address of the `st_table`.
+    weak_table_watermarks: Mutex<HashMap<usize, WeakTableWatermark>>,
+    /// Weak tables registered with `mmtk_register_weak_table`.
+    registered_weak_tables: Mutex<Vec<RegisteredWeakTable>>,
 }
 
 impl Default for WeakProcessor {
@@ -28,33 +179,198 @@ impl Default for WeakProcessor {
 }
 
 impl WeakProcessor {
+    const OBJ_FREE_CANDIDATES_PER_PACKET: usize = 4096;
+
     pub fn new() -> Self {
         Self {
-            obj_free_candidates: Mutex::new(Vec::new()),
+            obj_free_candidates: Default::default(),
+            obj_free_buffers: Default::default(),
+            pending_obj_free: Default::default(),
+            newly_dead_obj_free: Default::default(),
+            weak_refs_processed: AtomicBool::new(false),
+            obj_free_resurrected: AtomicBool::new(false),
+            weak_table_watermarks: Default::default(),
+            registered_weak_tables: Default::default(),
         }
     }
 
     /// Add an object as a candidate for `obj_free`.
     ///
-    /// Multiple mutators can call it concurrently, so it has `&self`.
+    /// Multiple mutators can call it concurrently, so it has `&self`.  Candidates are added to
+    /// the buffer of the current thread without locking.
     pub fn add_obj_free_candidate(&self, object: ObjectReference) {
-        let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
-        obj_free_candidates.push(object);
+        self.with_obj_free_buffer(|buffer| buffer.push(object));
     }
 
     /// Add many objects as candidates for `obj_free`.
     ///
     /// Multiple mutators can call it concurrently, so it has `&self`.
     pub fn add_obj_free_candidates(&self, objects: &[ObjectReference]) {
-        let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
-        for object in objects.iter().copied() {
-            obj_free_candidates.push(object);
+        self.with_obj_free_buffer(|buffer| buffer.extend_from_slice(objects));
+    }
+
+    fn with_obj_free_buffer(&self, f: impl FnOnce(&mut Vec<ObjectReference>)) {
+        if let Err(f) = ThreadBuffer::with_current(&OBJ_FREE_CANDIDATE_BUFFER, f) {
+            // The thread-local buffer has been destroyed because the thread is exiting.  This is
+            // rare, so we just take the lock.
+            let mut buffer = Vec::new();
+            f(&mut buffer);
+            self.obj_free_candidates.lock().unwrap().push(buffer);
         }
     }
 
+    /// Take all `obj_free` candidates, including those in per-thread buffers, as a list of
+    /// vectors.  Mutators must not be adding candidates concurrently.
+    fn take_obj_free_candidates(&self) -> Vec<Vec<ObjectReference>> {
+        let mut candidates = std::mem::take(&mut *self.obj_free_candidates.lock().unwrap());
+        candidates.append(&mut self.obj_free_buffers.take_all());
+        candidates
+    }
+
+    /// Get all `obj_free` candidates, including dead objects pending for `obj_free`.  Only called
+    /// when the VM exits, and other mutators must not be running.
     pub fn get_all_obj_free_candidates(&self) -> Vec<ObjectReference> {
-        let mut obj_free_candidates = self.obj_free_candidates.lock().unwrap();
-        std::mem::take(obj_free_candidates.as_mut())
+        let mut all = self.take_obj_free_candidates().concat();
+        all.append(&mut self.pending_obj_free.lock().unwrap());
+        all
+    }
+
+    /// Return true if weak references have been processed in the current GC, and the remaining
+    /// work is resurrecting dead `obj_free` candidates.
+    pub fn weak_refs_processed(&self) -> bool {
+        self.weak_refs_processed.load(Ordering::SeqCst)
+    }
+
+    /// Resurrect dead `obj_free` candidates in a later call of `process_weak_refs`.  Called after
+    /// scheduling all weak processing work, so that the candidates are resurrected after all weak
+    /// references to them have been cleared as if they were freed.
+    pub fn defer_obj_free_resurrection(&self) {
+        self.weak_refs_processed.store(true, Ordering::SeqCst);
+    }
+
+    /// Move dead `obj_free` candidates found in this GC to the pending queue, and trace them so
+    /// that their memory is not reclaimed before mutators free them.  Objects still pending from
+    /// the last GC are freed now instead of being traced again, because tracing them may reach
+    /// objects that mutators have already freed.
+    ///
+    /// Return true if any object is traced, in which case the caller must let mmtk-core finish
+    /// the transitive closure and call this function again.
+    pub fn resurrect_dead_obj_free_candidates(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        tracer_context: impl ObjectTracerContext<Ruby>,
+    ) -> bool {
+        if self.obj_free_resurrected.swap(true, Ordering::SeqCst) {
+            self.finish_obj_free_resurrection();
+            return false;
+        }
+
+        let mut pending = self
+            .pending_obj_free
+            .try_lock()
+            .expect("Mutators should not be draining pending objects during GC.");
+
+        // Nothing but other pending objects can reach them, so they can be freed while the
+        // closure from newly dead candidates is being computed.
+        let leftovers = std::mem::take(&mut *pending);
+        if !leftovers.is_empty() {
+            worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].add(
+                FreeObjectsSerially {
+                    objects: Arc::new(Mutex::new(leftovers)),
+                },
+            );
+        }
+
+        *pending = std::mem::take(&mut *self.newly_dead_obj_free.lock().unwrap());
+        debug!("Resurrecting {} dead obj_free candidates", pending.len());
+        if pending.is_empty() {
+            self.finish_obj_free_resurrection();
+            return false;
+        }
+
+        tracer_context.with_tracer(worker, |tracer| {
+            for object in pending.iter_mut() {
+                *object = tracer.trace_object(*object);
+            }
+        });
+
+        true
+    }
+
+    fn finish_obj_free_resurrection(&self) {
+        self.obj_free_resurrected.store(false, Ordering::SeqCst);
+        self.weak_refs_processed.store(false, Ordering::SeqCst);
+    }
+
+    /// Call `obj_free` on at most `max` pending objects.  Return the number of remaining objects.
+    pub fn drain_pending_obj_free(&self, max: usize) -> usize {
+        let (objects, remaining) = {
+            let mut pending = self.pending_obj_free.lock().unwrap();
+            let len = pending.len();
+            let objects = pending.split_off(len - max.min(len));
+            (objects, pending.len())
+        };
+        for object in objects {
+            (upcalls().call_obj_free)(object);
+        }
+        remaining
+    }
+
+    /// Register a weak table.  The table will be processed in parallel chunks in every GC until
+    /// it is unregistered.  If the table is already registered, its name and flags are updated.
+    ///
+    /// The name is only used for logging and tracing.  Return false without registering the table
+    /// if neither keys nor values are weak, because nothing would keep its referents alive.
+    pub fn register_weak_table(
+        &self,
+        table: *mut st_table,
+        name: &str,
+        weak_keys: bool,
+        weak_values: bool,
+        address_hashed: bool,
+    ) -> bool {
+        debug!("Registering weak table {name}: {table:?}, weak_keys: {weak_keys}, weak_values: {weak_values}, address_hashed: {address_hashed}");
+        if !weak_keys && !weak_values {
+            warn!("Refusing to register weak table {name} whose keys and values are both strong.");
+            return false;
+        }
+        let mut registered_weak_tables = self.registered_weak_tables.lock().unwrap();
+        let entry = RegisteredWeakTable {
+            table,
+            spec: WeakTableSpec {
+                name: Cow::Owned(name.to_string()),
+                kind: WeakTableKind::Generic {
+                    weak_keys,
+                    weak_values,
+                },
+                insert_only: false,
+                address_hashed,
+            },
+        };
+        if let Some(existing) = registered_weak_tables
+            .iter_mut()
+            .find(|entry| entry.table == table)
+        {
+            *existing = entry;
+        } else {
+            registered_weak_tables.push(entry);
+        }
+        true
+    }
+
+    /// Unregister a weak table.  Return false if the table was not registered.
+    pub fn unregister_weak_table(&self, table: *mut st_table) -> bool {
+        let removed = {
+            let mut registered_weak_tables = self.registered_weak_tables.lock().unwrap();
+            let old_len = registered_weak_tables.len();
+            registered_weak_tables.retain(|entry| entry.table != table);
+            registered_weak_tables.len() != old_len
+        };
+        if removed {
+            let mut watermarks = self.weak_table_watermarks.lock().unwrap();
+            watermarks.remove(&(table as usize));
+        }
+        removed
     }
 
     pub fn process_weak_stuff(
@@ -62,48 +378,139 @@ impl WeakProcessor {
         worker: &mut GCWorker<Ruby>,
         _tracer_context: impl ObjectTracerContext<Ruby>,
     ) {
-        worker.add_work(WorkBucketStage::VMRefClosure, ProcessObjFreeCandidates);
-
         worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(vec![
-            Box::new(UpdateGenericIvTbl) as _,
             // Box::new(UpdateFrozenStringsTable) as _,
-            Box::new(UpdateFinalizerAndObjIdTables) as _,
             // Box::new(UpdateGlobalSymbolsTable) as _,
-            Box::new(UpdateOverloadedCmeTable) as _,
-            Box::new(UpdateCiTable) as _,
             Box::new(UpdateWbUnprotectedObjectsList) as _,
         ]);
 
         let forward = crate::mmtk().get_plan().current_gc_may_move_object();
 
-        // Experimenting with frozen strings table
-        Self::process_weak_table_chunked(
-            "frozen strings",
+        self.process_weak_table_chunked(
+            &FROZEN_STRINGS_TABLE,
             (upcalls().get_frozen_strings_table)(),
-            true,
-            false,
             forward,
             worker,
         );
 
-        Self::process_weak_table_chunked(
-            "global symbols",
+        self.process_weak_table_chunked(
+            &GLOBAL_SYMBOLS_TABLE,
             (upcalls().get_global_symbols_table)(),
-            false,
-            true,
             forward,
             worker,
         );
+
+        self.process_weak_table_chunked(
+            &GENERIC_IV_TABLE,
+            (upcalls().get_generic_iv_tbl)(),
+            forward,
+            worker,
+        );
+
+        self.process_weak_table_chunked(
+            &OVERLOADED_CME_TABLE,
+            (upcalls().get_overloaded_cme_table)(),
+            forward,
+            worker,
+        );
+
+        self.process_weak_table_chunked(&CI_TABLE, (upcalls().get_ci_table)(), forward, worker);
+
+        if forward {
+            self.process_weak_table_chunked(
+                &FINALIZER_TABLE,
+                (upcalls().get_finalizer_table)(),
+                forward,
+                worker,
+            );
+        }
+
+        // Dead objects with finalizers have been resurrected by mmtk-core before this stage, so
+        // their IDs are kept for their finalizer jobs.
+        self.process_weak_table_chunked(
+            &OBJ_TO_ID_TABLE,
+            (upcalls().get_obj_to_id_table)(),
+            forward,
+            worker,
+        );
+        self.process_weak_table_chunked(
+            &ID_TO_OBJ_TABLE,
+            (upcalls().get_id_to_obj_table)(),
+            forward,
+            worker,
+        );
+
+        let registered_weak_tables = self
+            .registered_weak_tables
+            .try_lock()
+            .expect("Mutators should not be registering weak tables during GC.");
+        for entry in registered_weak_tables.iter() {
+            self.process_weak_table_chunked(&entry.spec, entry.table, forward, worker);
+        }
+    }
+
+    /// Create packets that process `obj_free` candidates in parallel chunks.  Each vector of
+    /// candidates is split into chunks of at most `OBJ_FREE_CANDIDATES_PER_PACKET` candidates
+    /// without copying.  The caller schedules them after weak slots are processed.
+    ///
+    /// Survivors of all packets are merged into full vectors, so the number of vectors does not
+    /// grow from GC to GC even if each packet only has a few survivors.
+    pub fn obj_free_candidate_packets(&self) -> Vec<Box<dyn GCWork<Ruby>>> {
+        let serial_frees = Arc::new(Mutex::new(Vec::new()));
+        let survivors = Arc::new(Mutex::new(ChunkedVecCollector::new(
+            Self::OBJ_FREE_CANDIDATES_PER_PACKET,
+        )));
+        let after_all = Arc::new(AfterAll::new(WorkBucketStage::VMRefClosure));
+
+        let mut packets: Vec<Box<dyn GCWork<Ruby>>> = vec![];
+        for candidates in self.take_obj_free_candidates() {
+            let candidates = Arc::new(candidates);
+            for begin in (0..candidates.len()).step_by(Self::OBJ_FREE_CANDIDATES_PER_PACKET) {
+                let end = (begin + Self::OBJ_FREE_CANDIDATES_PER_PACKET).min(candidates.len());
+                packets.push(Box::new(ProcessObjFreeCandidates {
+                    candidates: candidates.clone(),
+                    begin,
+                    end,
+                    serial_frees: serial_frees.clone(),
+                    survivors: survivors.clone(),
+                    after_all: after_all.clone(),
+                }));
+            }
+        }
+
+        if packets.is_empty() {
+            return packets;
+        }
+
+        after_all.count_up(packets.len());
+        after_all.add_packets(vec![
+            Box::new(FreeObjectsSerially {
+                objects: serial_frees,
+            }),
+            Box::new(SaveObjFreeSurvivors { survivors }),
+        ]);
+        packets
     }
 
+    /// Process a weak table in parallel chunks.
+    ///
+    /// If `spec.insert_only` is true, a nursery GC only visits entries inserted since the last GC
+    /// because older entries only refer to mature objects, which are neither reclaimed nor moved
+    /// in nursery GCs.  We fall back to processing the whole table if the table has been rebuilt
+    /// since the last GC, because rebuilding reorders the entries.
+    ///
+    /// After all entries are updated, the bins are updated in parallel chunks, too.  But if
+    /// `spec.address_hashed` is true and objects may have been moved, the table is rehashed
+    /// instead, in parallel chunks if the VM provides `st_clear_bins_range` and
+    /// `st_rehash_entries_range`, or in one work packet otherwise.
     pub fn process_weak_table_chunked(
-        name: &'static str,
+        &self,
+        spec: &WeakTableSpec,
         table: *mut st_table,
-        weak_keys: bool,
-        weak_values: bool,
         forward: bool,
         worker: &mut GCWorker<Ruby>,
     ) {
+        let name: Arc<str> = Arc::from(spec.name.as_ref());
         let mut entries_start = 0;
         let mut entries_bound = 0;
         let mut bins_num = 0;
@@ -113,6 +520,18 @@ impl WeakProcessor {
             "name: {name}, entries_start: {entries_start}, entries_bound: {entries_bound}, bins_num: {bins_num}, num_entries: {num_entries}"
         );
 
+        let is_nursery_gc = (crate::mmtk().get_plan().generational())
+            .is_some_and(|gen| gen.is_current_gc_nursery());
+        let partial_start = if spec.insert_only && is_nursery_gc {
+            self.young_entries_start(table, entries_start, entries_bound)
+        } else {
+            None
+        };
+        let partial = partial_start.is_some();
+        let all_entries_start = entries_start;
+        let entries_start = partial_start.unwrap_or(entries_start);
+        debug!("name: {name}, partial: {partial}, visiting entries from {entries_start}");
+
         let table_name_ptr = name.as_ptr();
         let table_name_len = name.len();
 
@@ -127,105 +546,145 @@ impl WeakProcessor {
             table_name_len,
         );
 
-        let entries_chunk_size = crate::binding().st_entries_chunk_size;
-        let bins_chunk_size = crate::binding().st_bins_chunk_size;
+        let tuning = &crate::binding().tuning;
+        let entries_chunk_size =
+            tuning.chunk_size_for(tuning.st_entries_chunk_size, entries_bound - entries_start);
+        let bins_chunk_size = tuning.chunk_size_for(tuning.st_bins_chunk_size, bins_num);
+
+        if entries_start == entries_bound {
+            debug!("name: {name}, no entries to visit.");
+            self.record_watermark(table);
+            return;
+        }
 
         let after_all = Arc::new(AfterAll::new(WorkBucketStage::VMRefClosure));
+        let deleted_entries = Arc::new(AtomicUsize::new(0));
 
         let entries_packets = (entries_start..entries_bound)
             .step_by(entries_chunk_size)
             .map(|begin| {
                 let end = (begin + entries_chunk_size).min(entries_bound);
                 let after_all = after_all.clone();
+                let deleted_entries = deleted_entries.clone();
                 Box::new(UpdateTableEntriesParallel {
-                    name,
+                    name: name.clone(),
                     table,
                     begin,
                     end,
-                    weak_keys,
-                    weak_values,
+                    kind: spec.kind,
                     forward,
                     after_all,
+                    deleted_entries,
                 }) as _
             })
             .collect::<Vec<_>>();
         after_all.count_up(entries_packets.len());
 
-        let bins_packets = (0..bins_num)
-            .step_by(entries_chunk_size)
-            .map(|begin| {
-                let end = (begin + bins_chunk_size).min(bins_num);
-                Box::new(UpdateTableBinsParallel {
-                    name: name.to_string(),
+        if forward && spec.address_hashed {
+            let upcalls = upcalls();
+            if upcalls.st_clear_bins_range.is_some() && upcalls.st_rehash_entries_range.is_some() {
+                schedule_parallel_rehash(
+                    &name,
                     table,
-                    begin,
-                    end,
-                }) as _
-            })
-            .collect::<Vec<_>>();
-        after_all.add_packets(bins_packets);
+                    spec.kind,
+                    (all_entries_start, entries_bound, entries_chunk_size),
+                    (bins_num, bins_chunk_size),
+                    &after_all,
+                );
+            } else {
+                after_all.add_packets(vec![Box::new(RehashTable {
+                    name: name.clone(),
+                    table,
+                    kind: spec.kind,
+                })]);
+            }
+        } else {
+            let bins_packets = (0..bins_num)
+                .step_by(bins_chunk_size)
+                .map(|begin| {
+                    let end = (begin + bins_chunk_size).min(bins_num);
+                    Box::new(UpdateTableBinsParallel {
+                        name: name.to_string(),
+                        table,
+                        begin,
+                        end,
+                        partial,
+                        deleted_entries: deleted_entries.clone(),
+                    }) as _
+                })
+                .collect::<Vec<_>>();
+            after_all.add_packets(bins_packets);
+        }
 
         worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure].bulk_add(entries_packets);
     }
 
-    /// Update generic instance variable tables.
-    ///
-    /// Objects moved during GC should have their entries in the global `generic_iv_tbl_` hash
-    /// table updated, and dead objects should have their entries removed.
-    fn update_generic_iv_tbl() {
-        // Update `generic_iv_tbl_` entries for moved objects.  We could update the entries in
-        // `ObjectModel::move`.  However, because `st_table` is not thread-safe, we postpone the
-        // update until now in the VMRefClosure stage.
-        log::debug!("Updating global ivtbl entries...");
-        let items_moved = {
-            let mut moved_givtbl = crate::binding()
-                .moved_givtbl
-                .try_lock()
-                .expect("Should have no race in weak_proc");
-            let items_moved = moved_givtbl.len();
-            for (new_objref, MovedGIVTblEntry { old_objref, .. }) in moved_givtbl.drain() {
-                trace!("  givtbl {} -> {}", old_objref, new_objref);
-                (upcalls().move_givtbl)(old_objref, new_objref);
-            }
-            items_moved
-        };
-        log::debug!("Updated global ivtbl entries.  {items_moved} items moved.");
-
-        // Clean up entries for dead objects.
-        let generic_iv_tbl = (upcalls().get_generic_iv_tbl)();
-        let old_size = (upcalls().st_get_num_entries)(generic_iv_tbl);
-        log::debug!("Cleaning up global ivtbl entries ({old_size} entries before)...");
-        (crate::upcalls().cleanup_generic_iv_tbl)();
-        let new_size = (upcalls().st_get_num_entries)(generic_iv_tbl);
-        log::debug!("Cleaning up global ivtbl entries ({new_size} entries after).");
-        probe!(
-            mmtk_ruby,
-            update_generic_iv_tbl,
-            items_moved,
-            old_size,
-            new_size
+    /// Return the index of the first entry inserted since the last GC, or `None` if we can't
+    /// tell which entries are new.
+    fn young_entries_start(
+        &self,
+        table: *mut st_table,
+        entries_start: usize,
+        entries_bound: usize,
+    ) -> Option<usize> {
+        // Packets of tables scheduled earlier may be recording their watermarks at the same time.
+        let watermarks = self.weak_table_watermarks.lock().unwrap();
+        let watermark = watermarks.get(&(table as usize))?;
+        let rebuilds_num = (upcalls().st_get_rebuilds_num)(table);
+        if watermark.rebuilds_num != rebuilds_num || watermark.entries_bound > entries_bound {
+            return None;
+        }
+        Some(watermark.entries_bound.max(entries_start))
+    }
+
+    /// Remember the current state of `table` so that the next nursery GC can find entries
+    /// inserted after this GC.
+    fn record_watermark(&self, table: *mut st_table) {
+        let mut entries_start = 0;
+        let mut entries_bound = 0;
+        let mut bins_num = 0;
+        (upcalls().st_get_size_info)(table, &mut entries_start, &mut entries_bound, &mut bins_num);
+        let rebuilds_num = (upcalls().st_get_rebuilds_num)(table);
+        let mut watermarks = self.weak_table_watermarks.lock().unwrap();
+        watermarks.insert(
+            table as usize,
+            WeakTableWatermark {
+                rebuilds_num,
+                entries_bound,
+            },
         );
     }
 }
 
-struct ProcessObjFreeCandidates;
+/// Process a range of a vector of `obj_free` candidates.
+///
+/// With `RubyBindingOptions::lazy_obj_free`, dead candidates are collected into
+/// `WeakProcessor::newly_dead_obj_free` for mutators to free.  Otherwise, dead candidates that can
+/// be freed in parallel are freed immediately.  Others are collected
+/// into `serial_frees`, and are freed by one `FreeObjectsSerially` packet after all
+/// `ProcessObjFreeCandidates` packets of this GC finish.  Survivors are collected into
+/// `survivors`, and are saved by one `SaveObjFreeSurvivors` packet at the same time.
+struct ProcessObjFreeCandidates {
+    candidates: Arc<Vec<ObjectReference>>,
+    begin: usize,
+    end: usize,
+    serial_frees: Arc<Mutex<Vec<ObjectReference>>>,
+    survivors: Arc<Mutex<ChunkedVecCollector<ObjectReference>>>,
+    after_all: Arc<AfterAll>,
+}
 
 impl GCWork<Ruby> for ProcessObjFreeCandidates {
-    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
-        // If it blocks, it is a bug.
-        let mut obj_free_candidates = crate::binding()
-            .weak_proc
-            .obj_free_candidates
-            .try_lock()
-            .expect("It's GC time.  No mutators should hold this lock at this time.");
-
-        let old_cands = obj_free_candidates.len();
-        debug!("Total: {} candidates", old_cands);
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let old_cands = self.end - self.begin;
+        debug!("Processing {} candidates", old_cands);
 
         // Process obj_free
+        let lazy_obj_free = crate::binding().options.lazy_obj_free;
         let mut new_candidates = Vec::new();
+        let mut newly_dead = Vec::new();
+        let mut serial_frees = Vec::new();
 
-        for object in obj_free_candidates.iter().copied() {
+        for object in self.candidates[self.begin..self.end].iter().copied() {
             if object.is_reachable() {
                 // Forward and add back to the candidate list.
                 let new_object = object.forward();
@@ -235,14 +694,71 @@ impl GCWork<Ruby> for ProcessObjFreeCandidates {
                     new_object
                 );
                 new_candidates.push(new_object);
-            } else {
+            } else if lazy_obj_free {
+                newly_dead.push(object);
+            } else if (upcalls().can_free_in_parallel)(object) {
                 (upcalls().call_obj_free)(object);
+            } else {
+                serial_frees.push(object);
             }
         }
 
         let new_cands = new_candidates.len();
-        *obj_free_candidates = new_candidates;
+        if !new_candidates.is_empty() {
+            self.survivors.lock().unwrap().extend(new_candidates);
+        }
+        if !newly_dead.is_empty() {
+            crate::binding()
+                .weak_proc
+                .newly_dead_obj_free
+                .lock()
+                .unwrap()
+                .append(&mut newly_dead);
+        }
+        if !serial_frees.is_empty() {
+            self.serial_frees.lock().unwrap().append(&mut serial_frees);
+        }
         probe!(mmtk_ruby, process_obj_free_candidates, old_cands, new_cands);
+
+        self.after_all.count_down(worker);
+    }
+}
+
+/// Move the survivors of all `ProcessObjFreeCandidates` packets of this GC back to the candidate
+/// list.
+struct SaveObjFreeSurvivors {
+    survivors: Arc<Mutex<ChunkedVecCollector<ObjectReference>>>,
+}
+
+impl GCWork<Ruby> for SaveObjFreeSurvivors {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let survivors = std::mem::replace(
+            &mut *self.survivors.lock().unwrap(),
+            ChunkedVecCollector::new(WeakProcessor::OBJ_FREE_CANDIDATES_PER_PACKET),
+        );
+        let mut vecs = survivors.into_vecs();
+        debug!("Saving {} vectors of obj_free survivors", vecs.len());
+        let mut obj_free_candidates = crate::binding()
+            .weak_proc
+            .obj_free_candidates
+            .lock()
+            .unwrap();
+        obj_free_candidates.append(&mut vecs);
+    }
+}
+
+/// Call `obj_free` on dead objects whose free functions are not thread-safe, one at a time.
+struct FreeObjectsSerially {
+    objects: Arc<Mutex<Vec<ObjectReference>>>,
+}
+
+impl GCWork<Ruby> for FreeObjectsSerially {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        let objects = std::mem::take(&mut *self.objects.lock().unwrap());
+        debug!("Freeing {} objects serially", objects.len());
+        for object in objects {
+            (upcalls().call_obj_free)(object);
+        }
     }
 }
 
@@ -289,10 +805,6 @@ macro_rules! define_global_table_processor {
     };
 }
 
-define_global_table_processor!(UpdateGenericIvTbl, {
-    WeakProcessor::update_generic_iv_tbl();
-});
-
 fn general_update_weak_table(getter: extern "C" fn() -> *mut st_table, cleaner: extern "C" fn()) {
     let table = getter();
     let old_size = (upcalls().st_get_num_entries)(table);
@@ -319,53 +831,16 @@ mod unused {
     });
 }
 
-define_global_table_processor!(UpdateFinalizerAndObjIdTables, {
-    let finalizer_table = (upcalls().get_finalizer_table)();
-    let obj_to_id_table = (upcalls().get_obj_to_id_table)();
-    let id_to_obj_table = (upcalls().get_id_to_obj_table)();
-
-    let old_size_finalizer = (upcalls().st_get_num_entries)(finalizer_table);
-    let old_size_obj_to_id = (upcalls().st_get_num_entries)(obj_to_id_table);
-    let old_size_id_to_obj = (upcalls().st_get_num_entries)(id_to_obj_table);
-
-    (upcalls().update_finalizer_and_obj_id_tables)();
-
-    let new_size_finalizer = (upcalls().st_get_num_entries)(finalizer_table);
-    let new_size_obj_to_id = (upcalls().st_get_num_entries)(obj_to_id_table);
-    let new_size_id_to_obj = (upcalls().st_get_num_entries)(id_to_obj_table);
-
-    probe!(
-        mmtk_ruby,
-        update_finalizer_and_obj_id_tables,
-        old_size_finalizer,
-        new_size_finalizer,
-        old_size_obj_to_id,
-        new_size_obj_to_id,
-        old_size_id_to_obj,
-        new_size_id_to_obj,
-    );
-});
-
-define_global_table_processor!(UpdateOverloadedCmeTable, {
-    general_update_weak_table(
-        upcalls().get_overloaded_cme_table,
-        upcalls().update_overloaded_cme_table,
-    );
-});
-
-define_global_table_processor!(UpdateCiTable, {
-    general_update_weak_table(upcalls().get_ci_table, upcalls().update_ci_table);
-});
-
 struct UpdateTableEntriesParallel {
-    name: &'static str,
+    name: Arc<str>,
     table: *mut st_table,
     begin: usize,
     end: usize,
-    weak_keys: bool,
-    weak_values: bool,
+    kind: WeakTableKind,
     forward: bool,
     after_all: Arc<AfterAll>,
+    /// The number of deleted entries of the table, accumulated over all chunks.
+    deleted_entries: Arc<AtomicUsize>,
 }
 
 unsafe impl Send for UpdateTableEntriesParallel {}
@@ -375,15 +850,28 @@ impl UpdateTableEntriesParallel {}
 impl GCWork<Ruby> for UpdateTableEntriesParallel {
     fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
         debug!("Updating entries of {} table", self.name);
-        let deleted_entries = (upcalls().st_update_entries_range)(
-            self.table,
-            self.begin,
-            self.end,
-            self.weak_keys,
-            self.weak_values,
-            self.forward,
-        );
+        let deleted_entries = match self.kind {
+            WeakTableKind::Generic {
+                weak_keys,
+                weak_values,
+            } => (upcalls().st_update_entries_range)(
+                self.table,
+                self.begin,
+                self.end,
+                weak_keys,
+                weak_values,
+                self.forward,
+            ),
+            WeakTableKind::GenericIvTbl => (upcalls().update_generic_iv_tbl_entries_range)(
+                self.table,
+                self.begin,
+                self.end,
+                self.forward,
+            ),
+        };
         debug!("Done updating entries of {} table", self.name);
+        self.deleted_entries
+            .fetch_add(deleted_entries, Ordering::SeqCst);
         let table_name = self.name.as_ptr();
         let table_name_len = self.name.len();
         probe!(
@@ -398,6 +886,7 @@ impl GCWork<Ruby> for UpdateTableEntriesParallel {
 
         let is_last = self.after_all.count_down(worker);
         if is_last {
+            crate::binding().weak_proc.record_watermark(self.table);
             let num_entries = (upcalls().st_get_num_entries)(self.table);
             probe!(
                 mmtk_ruby,
@@ -415,6 +904,10 @@ struct UpdateTableBinsParallel {
     table: *mut st_table,
     begin: usize,
     end: usize,
+    /// True if only entries inserted since the last GC were visited.
+    partial: bool,
+    /// The number of entries deleted by all `UpdateTableEntriesParallel` packets of the table.
+    deleted_entries: Arc<AtomicUsize>,
 }
 
 unsafe impl Send for UpdateTableBinsParallel {}
@@ -423,6 +916,13 @@ impl UpdateTableBinsParallel {}
 
 impl GCWork<Ruby> for UpdateTableBinsParallel {
     fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        // Bins packets are only executed after all entries packets of the same table.  If we
+        // only visited young entries and none of them were deleted, no bins need updating
+        // because mature objects are not moved in nursery GCs.
+        if self.partial && self.deleted_entries.load(Ordering::SeqCst) == 0 {
+            debug!("Skipped updating bins of {} table", self.name);
+            return;
+        }
         debug!("Updating bins of {} table", self.name);
         let deleted_bins = (upcalls().st_update_bins_range)(self.table, self.begin, self.end);
         debug!("Done updating bins of {} table", self.name);
@@ -440,29 +940,205 @@ impl GCWork<Ruby> for UpdateTableBinsParallel {
     }
 }
 
+/// Schedule packets that rehash a table in parallel after all of its entries are updated, i.e. when
+/// `after_entries` reaches zero.  All bins are cleared in parallel chunks first, and then the live
+/// entries are inserted into the bins in parallel chunks.
+fn schedule_parallel_rehash(
+    name: &Arc<str>,
+    table: *mut st_table,
+    kind: WeakTableKind,
+    (entries_start, entries_bound, entries_chunk_size): (usize, usize, usize),
+    (bins_num, bins_chunk_size): (usize, usize),
+    after_entries: &AfterAll,
+) {
+    debug!("name: {name}, rehashing in parallel");
+    let after_rehash = Arc::new(AfterAll::new(WorkBucketStage::VMRefClosure));
+    let rehash_packets = (entries_start..entries_bound)
+        .step_by(entries_chunk_size)
+        .map(|begin| {
+            let end = (begin + entries_chunk_size).min(entries_bound);
+            Box::new(RehashTableEntriesParallel {
+                name: name.clone(),
+                table,
+                begin,
+                end,
+                after_all: after_rehash.clone(),
+            }) as _
+        })
+        .collect::<Vec<_>>();
+    after_rehash.count_up(rehash_packets.len());
+    if let WeakTableKind::GenericIvTbl = kind {
+        after_rehash.add_packets(vec![Box::new(ForgetMovedGIVTbl)]);
+    }
+
+    if bins_num == 0 {
+        // Small tables have no bins.  We only need to recompute the hashes.
+        after_entries.add_packets(rehash_packets);
+        return;
+    }
+
+    let after_clear = Arc::new(AfterAll::new(WorkBucketStage::VMRefClosure));
+    after_clear.add_packets(rehash_packets);
+    let clear_packets = (0..bins_num)
+        .step_by(bins_chunk_size)
+        .map(|begin| {
+            let end = (begin + bins_chunk_size).min(bins_num);
+            Box::new(ClearTableBinsParallel {
+                name: name.clone(),
+                table,
+                begin,
+                end,
+                after_all: after_clear.clone(),
+            }) as _
+        })
+        .collect::<Vec<_>>();
+    after_clear.count_up(clear_packets.len());
+    after_entries.add_packets(clear_packets);
+}
+
+/// Now that moved objects can find their `gen_ivtbl` in `generic_iv_tbl_` using their new
+/// addresses, we no longer need the entries recorded during copying.
+fn forget_moved_givtbl() {
+    let mut moved_givtbl = crate::binding()
+        .moved_givtbl
+        .try_lock()
+        .expect("Should have no race in weak_proc");
+    let items_moved = moved_givtbl.len();
+    for (new_objref, MovedGIVTblEntry { old_objref, .. }) in moved_givtbl.drain() {
+        trace!("  givtbl {} -> {}", old_objref, new_objref);
+    }
+    debug!("{items_moved} givtbl entries moved.");
+}
+
+struct ClearTableBinsParallel {
+    name: Arc<str>,
+    table: *mut st_table,
+    begin: usize,
+    end: usize,
+    after_all: Arc<AfterAll>,
+}
+
+unsafe impl Send for ClearTableBinsParallel {}
+
+impl GCWork<Ruby> for ClearTableBinsParallel {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        debug!(
+            "Clearing bins {}..{} of {} table",
+            self.begin, self.end, self.name
+        );
+        (upcalls().st_clear_bins_range.unwrap())(self.table, self.begin, self.end);
+        self.after_all.count_down(worker);
+    }
+}
+
+struct RehashTableEntriesParallel {
+    name: Arc<str>,
+    table: *mut st_table,
+    begin: usize,
+    end: usize,
+    after_all: Arc<AfterAll>,
+}
+
+unsafe impl Send for RehashTableEntriesParallel {}
+
+impl GCWork<Ruby> for RehashTableEntriesParallel {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        debug!(
+            "Rehashing entries {}..{} of {} table",
+            self.begin, self.end, self.name
+        );
+        (upcalls().st_rehash_entries_range.unwrap())(self.table, self.begin, self.end);
+        self.after_all.count_down(worker);
+    }
+}
+
+/// Forget the `gen_ivtbl` of moved objects after `generic_iv_tbl_` is rehashed in parallel.
+struct ForgetMovedGIVTbl;
+
+impl GCWork<Ruby> for ForgetMovedGIVTbl {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        forget_moved_givtbl();
+    }
+}
+
+/// Rehash a table after its keys have been forwarded.  This replaces `UpdateTableBinsParallel`
+/// for tables whose keys are hashed by address if the VM can't rehash tables in parallel.
+struct RehashTable {
+    name: Arc<str>,
+    table: *mut st_table,
+    kind: WeakTableKind,
+}
+
+unsafe impl Send for RehashTable {}
+
+impl GCWork<Ruby> for RehashTable {
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
+        debug!("Rehashing {} table", self.name);
+        (upcalls().st_rehash)(self.table);
+        debug!("Done rehashing {} table", self.name);
+
+        if let WeakTableKind::GenericIvTbl = self.kind {
+            forget_moved_givtbl();
+        }
+    }
+}
+
 struct UpdateWbUnprotectedObjectsList;
 
 impl GCWork<Ruby> for UpdateWbUnprotectedObjectsList {
-    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, _mmtk: &'static mmtk::MMTK<Ruby>) {
-        let mut objects = crate::binding().wb_unprotected_objects.try_lock().expect(
+    fn do_work(&mut self, _worker: &mut GCWorker<Ruby>, mmtk: &'static mmtk::MMTK<Ruby>) {
+        let binding = crate::binding();
+        let mut objects = binding.wb_unprotected_objects.try_lock().expect(
             "Someone is holding the lock of wb_unprotected_objects during weak processing phase?",
         );
+        let mut young_objects = binding.young_wb_unprotected_objects.try_lock().expect(
+            "Someone is holding the lock of young_wb_unprotected_objects during weak processing phase?",
+        );
+        let bits = &binding.wb_unprotected_bits;
+        let dirty_bits = &binding.wb_unprotected_dirty_bits;
+        let escaped_bits = &binding.wb_unprotected_escaped_bits;
 
-        let old_objects = std::mem::take(&mut *objects);
-        let old_size = old_objects.len();
+        let old_size = objects.len() + young_objects.len();
 
-        debug!("Updating {old_size} WB-unprotected objects");
+        // In nursery GCs, old objects neither die nor move.  Only visit young objects.
+        let is_nursery_gc =
+            (mmtk.get_plan().generational()).is_some_and(|gen| gen.is_current_gc_nursery());
+        let mut old_objects = std::mem::take(&mut *young_objects);
+        if !is_nursery_gc {
+            old_objects.append(&mut objects);
+        }
 
-        for object in old_objects {
+        debug!(
+            "Updating {} WB-unprotected objects, nursery: {is_nursery_gc}",
+            old_objects.len()
+        );
+
+        // Clear all bits before setting the bits of new addresses.  An object may be moved to
+        // the old address of another object in the list.  Dirty bits are simply cleared because
+        // the visited objects have just been traced, and all their referents are now old.
+        let escaped = old_objects
+            .iter()
+            .map(|object| {
+                bits.clear(*object);
+                dirty_bits.clear(*object);
+                escaped_bits.clear(*object)
+            })
+            .collect::<Vec<_>>();
+
+        for (object, escaped) in old_objects.into_iter().zip(escaped) {
             if object.is_reachable() {
-                // Forward and add back to the candidate list.
+                // Forward and add back to the list.
                 let new_object = object.forward();
                 trace!(
                     "Forwarding WB-unprotected object: {} -> {}",
                     object,
                     new_object
                 );
-                objects.insert(new_object);
+                bits.set(new_object);
+                if escaped {
+                    escaped_bits.set(new_object);
+                }
+                objects.push(new_object);
             } else {
                 trace!("Removing WB-unprotected object from list: {}", object);
             }
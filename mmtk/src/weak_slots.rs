@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use mmtk::{
+    scheduler::{GCWork, GCWorker, WorkBucketStage},
+    util::Address,
+    vm::{ObjectTracer, ObjectTracerContext},
+    MMTK,
+};
+
+use crate::{
+    abi::value_to_objref,
+    extra_assert, is_mmtk_object_safe,
+    utils::{AfterAll, ChunkedVecCollector},
+    Ruby,
+};
+
+/// A registry of weak `VALUE` slots.
+///
+/// A weak slot is a memory location that holds a `VALUE` without keeping the object alive.  After
+/// the transitive closure of each GC, slots that refer to dead objects are cleared, and slots that
+/// refer to live objects are forwarded.  It is intended for `WeakRef`, `ObjectSpace::WeakMap` and
+/// C extensions that need weak references without maintaining their own weak tables.
+///
+/// It also holds ephemerons.  An ephemeron is a pair of a key slot and a value slot.  The key is
+/// weak, and the value is kept alive only if the key is reachable from elsewhere.  This is what
+/// `ObjectSpace::WeakKeyMap` needs.
+///
+/// A slot must not be inside an object that may be moved by the GC, because we record the address
+/// of the slot.
+pub struct WeakSlotRegistry {
+    /// Maps each registered slot to the value to be written into it when its referent dies.
+    slots: Mutex<HashMap<Address, Address>>,
+    /// Maps the key slot of each ephemeron to its value slot and the value to be written into
+    /// both slots when the key dies.
+    ephemerons: Mutex<HashMap<Address, (Address, Address)>>,
+    /// Ephemerons whose keys have not been found reachable in the current GC.  `None` if we have
+    /// not started tracing ephemerons in the current GC.
+    pending_ephemerons: Mutex<Option<Vec<(Address, Address)>>>,
+}
+
+impl WeakSlotRegistry {
+    /// The number of slots processed in each work packet.
+    const SLOTS_PER_PACKET: usize = 4096;
+
+    pub fn new() -> Self {
+        Self {
+            slots: Default::default(),
+            ephemerons: Default::default(),
+            pending_ephemerons: Default::default(),
+        }
+    }
+
+    /// Register `slot` as a weak slot.  When the object it refers to dies, `cleared_value` will be
+    /// written into `slot`.  Registering a slot again replaces its `cleared_value`.
+    pub fn register(&self, slot: Address, cleared_value: Address) {
+        let mut slots = self.slots.lock().unwrap();
+        slots.insert(slot, cleared_value);
+    }
+
+    /// Unregister `slot`.  Return false if `slot` is not registered.
+    pub fn unregister(&self, slot: Address) -> bool {
+        let mut slots = self.slots.lock().unwrap();
+        slots.remove(&slot).is_some()
+    }
+
+    /// Register an ephemeron.  The object in `value_slot` is kept alive as long as the object in
+    /// `key_slot` is alive.  When the key dies, `cleared_value` will be written into both slots.
+    pub fn register_ephemeron(
+        &self,
+        key_slot: Address,
+        value_slot: Address,
+        cleared_value: Address,
+    ) {
+        let mut ephemerons = self.ephemerons.lock().unwrap();
+        ephemerons.insert(key_slot, (value_slot, cleared_value));
+    }
+
+    /// Unregister the ephemeron with `key_slot`.  Return false if it is not registered.
+    pub fn unregister_ephemeron(&self, key_slot: Address) -> bool {
+        let mut ephemerons = self.ephemerons.lock().unwrap();
+        ephemerons.remove(&key_slot).is_some()
+    }
+
+    /// Trace the values of ephemerons whose keys are reachable.  Return true if any value is
+    /// newly traced.  In that case, the caller must let mmtk-core finish the transitive closure
+    /// and call this function again, because newly reached objects may make more keys reachable.
+    /// It returns false when we reach a fixpoint.
+    pub fn trace_ephemeron_values(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        tracer_context: impl ObjectTracerContext<Ruby>,
+    ) -> bool {
+        let mut pending_ephemerons = self
+            .pending_ephemerons
+            .try_lock()
+            .expect("trace_ephemeron_values should only be called by one GC worker at a time.");
+        let pending = pending_ephemerons.get_or_insert_with(|| {
+            let ephemerons = self
+                .ephemerons
+                .try_lock()
+                .expect("Mutators should not be registering ephemerons during GC.");
+            ephemerons
+                .iter()
+                .map(|(key_slot, (value_slot, _))| (*key_slot, *value_slot))
+                .collect()
+        });
+
+        let num_pending = pending.len();
+        let mut num_traced = 0usize;
+
+        tracer_context.with_tracer(worker, |tracer| {
+            pending.retain(|(key_slot, value_slot)| {
+                let key = unsafe { key_slot.load::<Address>() };
+                let key_reachable = value_to_objref(key).map_or(true, |key| key.is_reachable());
+                if !key_reachable {
+                    return true;
+                }
+                let value = unsafe { value_slot.load::<Address>() };
+                if let Some(value) = value_to_objref(value) {
+                    if !value.is_reachable() {
+                        log::trace!("  Tracing ephemeron value: {} -> {}", key, value);
+                        tracer.trace_object(value);
+                        num_traced += 1;
+                    }
+                }
+                false
+            });
+        });
+
+        debug!("Ephemerons: {num_pending} pending, {num_traced} values traced.");
+
+        if num_traced == 0 {
+            // Fixpoint.  Remaining ephemerons have dead keys.  They will be cleared in
+            // `process_weak_slots`.
+            *pending_ephemerons = None;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Clear or forward all weak slots and ephemerons in parallel packets, and schedule `then`
+    /// after all of them finish.  Weak slots may be in memory owned by objects that die in this
+    /// GC, such as the `DATA_PTR` of a dead `WeakRef`, so the caller should free dead objects in
+    /// `then`.
+    pub fn process_weak_slots(
+        &self,
+        worker: &mut GCWorker<Ruby>,
+        then: Vec<Box<dyn GCWork<Ruby>>>,
+    ) {
+        let ephemeron_vecs = {
+            let ephemerons = self
+                .ephemerons
+                .try_lock()
+                .expect("Mutators should not be registering ephemerons during GC.");
+            let mut collector = ChunkedVecCollector::new(Self::SLOTS_PER_PACKET);
+            collector.extend(
+                ephemerons
+                    .iter()
+                    .map(|(key_slot, (value_slot, cleared))| (*key_slot, *value_slot, *cleared)),
+            );
+            collector.into_vecs()
+        };
+
+        let slot_vecs = {
+            let slots = self
+                .slots
+                .try_lock()
+                .expect("Mutators should not be registering weak slots during GC.");
+            let mut collector = ChunkedVecCollector::new(Self::SLOTS_PER_PACKET);
+            collector.extend(slots.iter().map(|(slot, cleared)| (*slot, *cleared)));
+            collector.into_vecs()
+        };
+
+        let after_all = Arc::new(AfterAll::new(WorkBucketStage::VMRefClosure));
+        let mut packets: Vec<Box<dyn GCWork<Ruby>>> = vec![];
+        packets.extend(ephemeron_vecs.into_iter().map(|ephemerons| {
+            Box::new(ProcessEphemerons {
+                ephemerons,
+                after_all: after_all.clone(),
+            }) as _
+        }));
+        packets.extend(slot_vecs.into_iter().map(|slots| {
+            Box::new(ProcessWeakSlots {
+                slots,
+                after_all: after_all.clone(),
+            }) as _
+        }));
+
+        let bucket = &worker.scheduler().work_buckets[WorkBucketStage::VMRefClosure];
+        if packets.is_empty() {
+            bucket.bulk_add(then);
+            return;
+        }
+        after_all.count_up(packets.len());
+        after_all.add_packets(then);
+        bucket.bulk_add(packets);
+    }
+}
+
+impl Default for WeakSlotRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ProcessWeakSlots {
+    slots: Vec<(Address, Address)>,
+    after_all: Arc<AfterAll>,
+}
+
+impl GCWork<Ruby> for ProcessWeakSlots {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+        let num_slots = self.slots.len();
+        let mut num_cleared = 0usize;
+        let mut num_forwarded = 0usize;
+
+        for (slot, cleared_value) in self.slots.iter().copied() {
+            let value = unsafe { slot.load::<Address>() };
+            let Some(object) = value_to_objref(value) else {
+                continue;
+            };
+            extra_assert!(
+                is_mmtk_object_safe(object.to_raw_address()),
+                "Weak slot {slot} holds {value}, which is not an MMTk object"
+            );
+            if object.is_reachable() {
+                if let Some(new_object) = object.get_forwarded_object() {
+                    log::trace!(
+                        "  Forwarding weak slot {}: {} -> {}",
+                        slot,
+                        object,
+                        new_object
+                    );
+                    unsafe { slot.store(new_object) };
+                    num_forwarded += 1;
+                }
+            } else {
+                log::trace!("  Clearing weak slot {}: {} is dead", slot, object);
+                unsafe { slot.store(cleared_value) };
+                num_cleared += 1;
+            }
+        }
+
+        probe!(
+            mmtk_ruby,
+            process_weak_slots,
+            num_slots,
+            num_cleared,
+            num_forwarded
+        );
+
+        self.after_all.count_down(worker);
+    }
+}
+
+struct ProcessEphemerons {
+    ephemerons: Vec<(Address, Address, Address)>,
+    after_all: Arc<AfterAll>,
+}
+
+impl GCWork<Ruby> for ProcessEphemerons {
+    fn do_work(&mut self, worker: &mut GCWorker<Ruby>, _mmtk: &'static MMTK<Ruby>) {
+        for (key_slot, value_slot, cleared_value) in self.ephemerons.iter().copied() {
+            let key = unsafe { key_slot.load::<Address>() };
+            let key_alive = value_to_objref(key).map_or(true, |key| key.is_reachable());
+            if !key_alive {
+                log::trace!("  Clearing ephemeron {}: key {} is dead", key_slot, key);
+                unsafe {
+                    key_slot.store(cleared_value);
+                    value_slot.store(cleared_value);
+                }
+                continue;
+            }
+
+            // The key is alive, and so is the value, because we have traced it.
+            for slot in [key_slot, value_slot] {
+                let value = unsafe { slot.load::<Address>() };
+                if let Some(object) = value_to_objref(value) {
+                    debug_assert!(object.is_reachable());
+                    if let Some(new_object) = object.get_forwarded_object() {
+                        unsafe { slot.store(new_object) };
+                    }
+                }
+            }
+        }
+
+        self.after_all.count_down(worker);
+    }
+}
@@ -4,7 +4,9 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
     if wp is not None:
         match name:
             case "pin_ppp_children":
-                num_ppps, num_no_longer_ppps, num_pinned_children = [int(x) for x in args]
+                num_ppps, num_no_longer_ppps, num_pinned_children, num_skipped_children = [
+                    int(x) for x in args
+                ]
                 num_still_ppps = num_ppps - num_no_longer_ppps
                 wp["args"] |= {
                     "num_ppps": {
@@ -13,6 +15,7 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                         "no_longer_ppps": num_no_longer_ppps,
                     },
                     "num_pinned_children": num_pinned_children,
+                    "num_skipped_children": num_skipped_children,
                 }
 
             case "remove_dead_ppps":
@@ -44,16 +47,6 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                     },
                 }
 
-            case "update_finalizer_and_obj_id_tables":
-                (finalizer_before, finalizer_after,
-                 obj_to_id_before, obj_to_id_after,
-                 id_to_obj_before, id_to_obj_after) = [int(x) for x in args]
-                wp["args"] |= {
-                    "finalizer": { "before": finalizer_before, "after": finalizer_after, "diff": finalizer_after - finalizer_before },
-                    "obj_to_id": { "before": obj_to_id_before, "after": obj_to_id_after, "diff": obj_to_id_after - obj_to_id_before },
-                    "id_to_obj": { "before": id_to_obj_before, "after": id_to_obj_after, "diff": id_to_obj_after - id_to_obj_before },
-                }
-
             case "initial_weak_table_stats":
                 entries_start, entries_bound, bins_num, num_entries = [int(x) for x in args[0:4]]
                 table_name = args[4]
@@ -105,17 +98,6 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                     "table_name": table_name,
                 }
 
-            case "update_generic_iv_tbl":
-                entries_moved, old_entries, new_entries = [int(x) for x in args[0:3]]
-                wp["args"] |= {
-                    "entries_moved": entries_moved,
-                    "entries": {
-                        "before": old_entries,
-                        "after": new_entries,
-                        "diff": new_entries - old_entries,
-                    },
-                }
-
             case "process_obj_free_candidates":
                 old_candidates, new_candidates = [int(x) for x in args[0:2]]
                 wp["args"] |= {
@@ -135,3 +117,13 @@ def enrich_meta_extra(log_processor, name, tid, ts, gc, wp, args):
                         "diff": after - before,
                     },
                 }
+
+            case "process_weak_slots":
+                num_slots, num_cleared, num_forwarded = [int(x) for x in args[0:3]]
+                wp["args"] |= {
+                    "weak_slots": {
+                        "total": num_slots,
+                        "cleared": num_cleared,
+                        "forwarded": num_forwarded,
+                    },
+                }